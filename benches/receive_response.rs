@@ -0,0 +1,58 @@
+#![warn(missing_debug_implementations, rust_2018_idioms)]
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use futures::{executor, AsyncRead};
+use http_proxy_client_async::flow::receive_response;
+use std::io::Result;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const RESPONSE: &[u8] = b"HTTP/1.1 200 Connection established\r\n\
+                          Via: 1.1 proxy.example.com\r\n\
+                          X-Custom: Sample Value\r\n\
+                          \r\n\
+                          this is already the proxied content";
+
+/// A pre-filled mock reader that returns at most `chunk` bytes per read.
+#[derive(Debug)]
+struct ChunkedReader {
+    data: &'static [u8],
+    chunk: usize,
+}
+
+impl AsyncRead for ChunkedReader {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        let n = buf.len().min(self.chunk).min(self.data.len());
+        buf[..n].copy_from_slice(&self.data[..n]);
+        self.data = &self.data[n..];
+        Poll::Ready(Ok(n))
+    }
+}
+
+fn run(chunk: usize, read_buf_size: usize) {
+    let mut reader = ChunkedReader {
+        data: RESPONSE,
+        chunk,
+    };
+    let mut read_buf = vec![0u8; read_buf_size];
+    let outcome = executor::block_on(receive_response(&mut reader, &mut read_buf)).unwrap();
+    assert_eq!(outcome.response_parts.status_code, 200);
+}
+
+fn bench_receive_response(c: &mut Criterion) {
+    let cases = [
+        ("single_read", RESPONSE.len(), 1024),
+        ("4_byte_read_buf", RESPONSE.len(), 4),
+        ("1_byte_reads", 1, 1024),
+    ];
+    for &(name, chunk, read_buf_size) in cases.iter() {
+        c.bench_function(name, |b| b.iter(|| run(chunk, read_buf_size)));
+    }
+}
+
+criterion_group!(benches, bench_receive_response);
+criterion_main!(benches);
@@ -0,0 +1,334 @@
+use crate::http::{HeaderMap, HeaderValue};
+use ::http::header::PROXY_AUTHENTICATE;
+use base64::Engine;
+use std::io::{Error, ErrorKind, Result};
+
+/// Encodes the credentials into base64, to swap in another implementation,
+/// e.g. a constant-time one.
+pub trait Base64Encoder {
+    /// Encodes `input` with the standard alphabet, with padding.
+    fn encode(&self, input: &[u8]) -> String;
+}
+
+/// The default encoder, with the `base64` crate.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct StandardBase64;
+
+impl Base64Encoder for StandardBase64 {
+    fn encode(&self, input: &[u8]) -> String {
+        base64::engine::general_purpose::STANDARD.encode(input)
+    }
+}
+
+/// Builds a `Proxy-Authorization` header value for the `Basic` scheme.
+pub fn basic_auth(username: &str, password: &str) -> HeaderValue {
+    basic_auth_with(&StandardBase64, username, password)
+        .expect("base64 output is always a valid header value")
+}
+
+/// Like [`basic_auth`], but with a custom `encoder`. Fails with `InvalidData`
+/// if its output can't be put in a header.
+pub fn basic_auth_with<E>(encoder: &E, username: &str, password: &str) -> Result<HeaderValue>
+where
+    E: Base64Encoder + ?Sized,
+{
+    let credentials = format!("{}:{}", username, password);
+    let encoded = encoder.encode(credentials.as_bytes());
+    HeaderValue::from_str(&format!("Basic {}", encoded))
+        .map_err(|err| Error::new(ErrorKind::InvalidData, err))
+}
+
+/// An authentication challenge offered by the proxy via the
+/// `Proxy-Authenticate` header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Challenge {
+    pub scheme: String,
+    /// Set for the schemes that take a single opaque token instead of the
+    /// parameters list, like `Negotiate`.
+    pub token68: Option<String>,
+    /// Parameter names are lowercased, values are unquoted.
+    pub params: Vec<(String, String)>,
+}
+
+impl Challenge {
+    pub fn is_scheme(&self, scheme: &str) -> bool {
+        self.scheme.eq_ignore_ascii_case(scheme)
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn realm(&self) -> Option<&str> {
+        self.param("realm")
+    }
+}
+
+/// Parses all the challenges offered via the `Proxy-Authenticate` headers.
+/// Parsing is lenient: a malformed header contributes the challenges parsed
+/// before the malformed part.
+pub fn challenges(headers: &HeaderMap) -> Vec<Challenge> {
+    let mut challenges = Vec::new();
+    for value in headers.get_all(PROXY_AUTHENTICATE) {
+        ChallengeParser::new(value.as_bytes()).parse_into(&mut challenges);
+    }
+    challenges
+}
+
+/// Checks whether any of the `Proxy-Authenticate` headers offers the `Basic`
+/// scheme.
+pub(crate) fn offers_basic(headers: &HeaderMap) -> bool {
+    challenges(headers)
+        .iter()
+        .any(|challenge| challenge.is_scheme("basic"))
+}
+
+/// Parser for the `1#challenge` grammar from RFC 7235.
+struct ChallengeParser<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> ChallengeParser<'a> {
+    fn new(buf: &'a [u8]) -> Self {
+        Self { buf, pos: 0 }
+    }
+
+    fn parse_into(mut self, challenges: &mut Vec<Challenge>) {
+        loop {
+            self.skip_while(|b| b == b' ' || b == b'\t' || b == b',');
+            if self.pos == self.buf.len() {
+                return;
+            }
+
+            let scheme = match self.token() {
+                Some(scheme) => scheme,
+                None => return,
+            };
+            let mut challenge = Challenge {
+                scheme,
+                token68: None,
+                params: Vec::new(),
+            };
+
+            self.skip_ows();
+            let save = self.pos;
+            match self.token68() {
+                Some(token68) => challenge.token68 = Some(token68),
+                None => {
+                    self.pos = save;
+                    self.params_into(&mut challenge.params);
+                }
+            }
+
+            challenges.push(challenge);
+        }
+    }
+
+    /// Reads the `#auth-param` list, stopping before anything that isn't a
+    /// parameter, i.e. the next challenge.
+    fn params_into(&mut self, params: &mut Vec<(String, String)>) {
+        loop {
+            let save = self.pos;
+            match self.param() {
+                Some(param) => params.push(param),
+                None => {
+                    self.pos = save;
+                    return;
+                }
+            }
+            self.skip_ows();
+            if self.peek() != Some(b',') {
+                return;
+            }
+            self.pos += 1;
+            self.skip_ows();
+        }
+    }
+
+    fn param(&mut self) -> Option<(String, String)> {
+        let name = self.token()?.to_ascii_lowercase();
+        self.skip_ows();
+        if self.peek() != Some(b'=') {
+            return None;
+        }
+        self.pos += 1;
+        self.skip_ows();
+        let value = match self.peek() {
+            Some(b'"') => self.quoted_string()?,
+            _ => self.token()?,
+        };
+        Some((name, value))
+    }
+
+    /// Reads a `token68` that must span up to the end of the challenge.
+    fn token68(&mut self) -> Option<String> {
+        let start = self.pos;
+        self.skip_while(|b| b.is_ascii_alphanumeric() || b"-._~+/".contains(&b));
+        if self.pos == start {
+            return None;
+        }
+        self.skip_while(|b| b == b'=');
+        let token68 = String::from_utf8_lossy(&self.buf[start..self.pos]).into_owned();
+        self.skip_ows();
+        match self.peek() {
+            None | Some(b',') => Some(token68),
+            _ => None,
+        }
+    }
+
+    fn token(&mut self) -> Option<String> {
+        let start = self.pos;
+        self.skip_while(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+        if self.pos == start {
+            return None;
+        }
+        Some(String::from_utf8_lossy(&self.buf[start..self.pos]).into_owned())
+    }
+
+    fn quoted_string(&mut self) -> Option<String> {
+        let mut value = Vec::new();
+        self.pos += 1;
+        loop {
+            match self.peek()? {
+                b'"' => {
+                    self.pos += 1;
+                    return Some(String::from_utf8_lossy(&value).into_owned());
+                }
+                b'\\' => {
+                    self.pos += 1;
+                    value.push(self.peek()?);
+                }
+                byte => value.push(byte),
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn skip_ows(&mut self) {
+        self.skip_while(|b| b == b' ' || b == b'\t');
+    }
+
+    fn skip_while(&mut self, predicate: impl Fn(u8) -> bool) {
+        while self.peek().is_some_and(&predicate) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.buf.get(self.pos).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn basic_auth_test() {
+        assert_eq!(basic_auth("hello", "world"), "Basic aGVsbG86d29ybGQ=");
+    }
+
+    #[test]
+    fn basic_auth_with_custom_encoder() -> Result<()> {
+        /// Records the inputs, and defers to the default encoder.
+        #[derive(Default)]
+        struct Recording(std::cell::RefCell<Vec<Vec<u8>>>);
+
+        impl Base64Encoder for Recording {
+            fn encode(&self, input: &[u8]) -> String {
+                self.0.borrow_mut().push(input.to_vec());
+                StandardBase64.encode(input)
+            }
+        }
+
+        let encoder = Recording::default();
+        let value = basic_auth_with(&encoder, "hello", "world")?;
+        assert_eq!(value, "Basic aGVsbG86d29ybGQ=");
+        assert_eq!(encoder.0.into_inner(), vec![b"hello:world".to_vec()]);
+        Ok(())
+    }
+
+    #[test]
+    fn offers_basic_test() {
+        let mut headers = HeaderMap::new();
+        assert!(!offers_basic(&headers));
+
+        headers.insert(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Digest realm=\"proxy\""),
+        );
+        assert!(!offers_basic(&headers));
+
+        headers.append(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("basic realm=\"proxy\""),
+        );
+        assert!(offers_basic(&headers));
+    }
+
+    #[test]
+    fn basic_challenge_test() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Basic realm=\"x\""),
+        );
+
+        let challenges = challenges(&headers);
+        assert_eq!(challenges.len(), 1);
+        assert!(challenges[0].is_scheme("basic"));
+        assert_eq!(challenges[0].realm(), Some("x"));
+        assert_eq!(challenges[0].token68, None);
+    }
+
+    #[test]
+    fn digest_challenge_test() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static(
+                "Digest realm=\"proxy@example.com\", qop=\"auth,auth-int\", \
+                 nonce=\"dcd98b7102dd2f0e\", algorithm=MD5, stale=FALSE",
+            ),
+        );
+
+        let challenges = challenges(&headers);
+        assert_eq!(challenges.len(), 1);
+        let challenge = &challenges[0];
+        assert!(challenge.is_scheme("Digest"));
+        assert_eq!(challenge.realm(), Some("proxy@example.com"));
+        assert_eq!(challenge.param("qop"), Some("auth,auth-int"));
+        assert_eq!(challenge.param("nonce"), Some("dcd98b7102dd2f0e"));
+        assert_eq!(challenge.param("algorithm"), Some("MD5"));
+        assert_eq!(challenge.param("stale"), Some("FALSE"));
+    }
+
+    #[test]
+    fn multiple_challenges_test() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Negotiate YIIBhQYGKwYBBQUCoIIBeTCC==, NTLM"),
+        );
+        headers.append(
+            PROXY_AUTHENTICATE,
+            HeaderValue::from_static("Digest realm=\"a \\\"b\\\"\", nonce=abc, Basic realm=x"),
+        );
+
+        let challenges = challenges(&headers);
+        let schemes: Vec<_> = challenges.iter().map(|c| c.scheme.as_str()).collect();
+        assert_eq!(schemes, ["Negotiate", "NTLM", "Digest", "Basic"]);
+        assert_eq!(
+            challenges[0].token68.as_deref(),
+            Some("YIIBhQYGKwYBBQUCoIIBeTCC==")
+        );
+        assert!(challenges[1].params.is_empty());
+        assert_eq!(challenges[2].realm(), Some("a \"b\""));
+        assert_eq!(challenges[2].param("nonce"), Some("abc"));
+        assert_eq!(challenges[3].realm(), Some("x"));
+    }
+}
@@ -0,0 +1,175 @@
+//! The `host:port` target of the `CONNECT` request, validated up front.
+
+use crate::flow::request::HostPort;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::fmt;
+use std::io::{Error, ErrorKind, Result};
+use std::net::Ipv6Addr;
+
+/// The host and port to tunnel to. IPv6 hosts are kept without the brackets,
+/// and get them when formatted.
+///
+/// ```
+/// # use http_proxy_client_async::authority::Authority;
+/// # use std::convert::TryFrom;
+/// let authority = Authority::try_from("[::1]:443").unwrap();
+/// assert_eq!(authority.host(), "::1");
+/// assert_eq!(authority.port(), 443);
+/// assert_eq!(authority.to_string(), "[::1]:443");
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Authority {
+    host: String,
+    port: u16,
+}
+
+impl Authority {
+    /// Fails with `InvalidInput` if the `host` is neither a valid hostname or
+    /// IPv4 address, nor an IPv6 address, optionally with a zone id, or if
+    /// the `port` is zero.
+    pub fn new(host: &str, port: u16) -> Result<Self> {
+        let bracketed = host
+            .strip_prefix('[')
+            .and_then(|host| host.strip_suffix(']'));
+        // In brackets, the zone id is percent-encoded, as in the URIs.
+        let host = match bracketed {
+            Some(host) => Cow::Owned(host.replacen("%25", "%", 1)),
+            None => Cow::Borrowed(host),
+        };
+        // Only the IPv6 addresses go in brackets.
+        let is_valid = is_valid_host(&host) && (bracketed.is_none() || host.contains(':'));
+        if !is_valid {
+            return Err(invalid(format!("invalid host {:?}", host)));
+        }
+        if port == 0 {
+            return Err(invalid("port zero is not a valid target"));
+        }
+        Ok(Self {
+            host: host.into_owned(),
+            port,
+        })
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+}
+
+impl TryFrom<&str> for Authority {
+    type Error = Error;
+
+    /// Parses `host:port`, with IPv6 hosts in brackets.
+    fn try_from(authority: &str) -> Result<Self> {
+        let (host, port) = authority
+            .rsplit_once(':')
+            .ok_or_else(|| invalid(format!("no port in {:?}", authority)))?;
+        if host.contains(':') && !host.starts_with('[') {
+            return Err(invalid(format!(
+                "IPv6 host must be bracketed in {:?}",
+                authority
+            )));
+        }
+        let port = port
+            .parse()
+            .map_err(|_| invalid(format!("invalid port in {:?}", authority)))?;
+        Self::new(host, port)
+    }
+}
+
+impl fmt::Display for Authority {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        HostPort(&self.host, self.port).fmt(f)
+    }
+}
+
+fn is_valid_host(host: &str) -> bool {
+    if host.contains(':') {
+        let addr = host.split_once('%').map_or(host, |(addr, _)| addr);
+        return addr.parse::<Ipv6Addr>().is_ok();
+    }
+    !host.is_empty()
+        && host
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || matches!(b, b'-' | b'.' | b'_' | b'~'))
+}
+
+fn invalid<E>(err: E) -> Error
+where
+    E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+    Error::new(ErrorKind::InvalidInput, err)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_ipv4() -> Result<()> {
+        let authority = Authority::try_from("127.0.0.1:8080")?;
+        assert_eq!(authority.host(), "127.0.0.1");
+        assert_eq!(authority.port(), 8080);
+        assert_eq!(authority.to_string(), "127.0.0.1:8080");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_ipv6() -> Result<()> {
+        let authority = Authority::try_from("[2001:db8::1]:443")?;
+        assert_eq!(authority.host(), "2001:db8::1");
+        assert_eq!(authority.to_string(), "[2001:db8::1]:443");
+
+        let authority = Authority::new("fe80::1%eth0", 443)?;
+        assert_eq!(authority.to_string(), "[fe80::1%25eth0]:443");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_hostname() -> Result<()> {
+        let authority = Authority::try_from("example.com:80")?;
+        assert_eq!(authority.host(), "example.com");
+        assert_eq!(authority.port(), 80);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_invalid() {
+        for authority in &[
+            "example.com",
+            "2001:db8::1:443",
+            "[not-ipv6]:443",
+            "example.com:0",
+            "example.com:65536",
+            "exa mple.com:80",
+            "user@example.com:80",
+            ":80",
+        ] {
+            let err = Authority::try_from(*authority).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{}", authority);
+        }
+    }
+
+    #[test]
+    fn round_trip() -> Result<()> {
+        for authority in &[
+            Authority::new("127.0.0.1", 8080)?,
+            Authority::new("example.com", 443)?,
+            Authority::new("2001:db8::1", 443)?,
+            Authority::new("fe80::1%eth0", 443)?,
+            Authority::try_from("[fe80::1%25eth0]:443")?,
+        ] {
+            let parsed = Authority::try_from(authority.to_string().as_str())?;
+            assert_eq!(&parsed, authority);
+        }
+        assert_eq!(
+            Authority::try_from("[fe80::1%25eth0]:443")?.host(),
+            "fe80::1%eth0"
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,171 @@
+//! A writer for the line-oriented protocols run over the tunnel, terminating
+//! every write with CRLF.
+
+use futures_io::{AsyncRead, AsyncWrite, IoSliceMut};
+use std::io::{ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+const CRLF: &[u8] = b"\r\n";
+
+/// Appends CRLF after every buffer that was written in full, e.g. with
+/// `write_all`, so that each one ends up as a line. A buffer written only
+/// partially is continued by the next write, as usual, and gets its CRLF once
+/// it's complete. Reads are passed through.
+#[derive(Debug)]
+pub struct CrlfWriter<W> {
+    inner: W,
+    /// How much of the CRLF after the last line is yet to be written.
+    pending_crlf: usize,
+}
+
+impl<W> CrlfWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    pub fn new(inner: W) -> Self {
+        Self {
+            inner,
+            pending_crlf: 0,
+        }
+    }
+
+    pub fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut W {
+        &mut self.inner
+    }
+
+    /// Unwraps the inner writer. The CRLF of the last line might be lost if
+    /// the writer wasn't flushed.
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+
+    fn poll_write_crlf(&mut self, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        while self.pending_crlf > 0 {
+            let crlf = &CRLF[CRLF.len() - self.pending_crlf..];
+            match Pin::new(&mut self.inner).poll_write(cx, crlf) {
+                Poll::Ready(Ok(0)) => return Poll::Ready(Err(ErrorKind::WriteZero.into())),
+                Poll::Ready(Ok(n)) => self.pending_crlf -= n,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<W> AsyncWrite for CrlfWriter<W>
+where
+    W: AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        futures_util::ready!(this.poll_write_crlf(cx))?;
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = poll {
+            if n == buf.len() && n != 0 {
+                this.pending_crlf = CRLF.len();
+            }
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        futures_util::ready!(this.poll_write_crlf(cx))?;
+        Pin::new(&mut this.inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        futures_util::ready!(this.poll_write_crlf(cx))?;
+        Pin::new(&mut this.inner).poll_close(cx)
+    }
+}
+
+impl<W> AsyncRead for CrlfWriter<W>
+where
+    W: AsyncRead + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_read(cx, buf)
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<Result<usize>> {
+        Pin::new(&mut self.get_mut().inner).poll_read_vectored(cx, bufs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor, io::Cursor, AsyncWriteExt};
+
+    /// A writer that accepts at most `chunk` bytes per write.
+    struct TrickleWriter {
+        written: Vec<u8>,
+        chunk: usize,
+    }
+
+    impl AsyncWrite for TrickleWriter {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize>> {
+            let n = buf.len().min(self.chunk);
+            self.written.extend_from_slice(&buf[..n]);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    #[test]
+    fn write_lines() -> Result<()> {
+        executor::block_on(async {
+            let mut writer = CrlfWriter::new(Cursor::new(vec![]));
+            writer.write_all(b"HELO example.com").await?;
+            writer.write_all(b"QUIT").await?;
+            writer.flush().await?;
+            assert_eq!(
+                writer.get_ref().get_ref().as_slice(),
+                b"HELO example.com\r\nQUIT\r\n"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn write_lines_partially() -> Result<()> {
+        executor::block_on(async {
+            let mut writer = CrlfWriter::new(TrickleWriter {
+                written: vec![],
+                chunk: 3,
+            });
+            writer.write_all(b"first").await?;
+            writer.write_all(b"second").await?;
+            writer.close().await?;
+            assert_eq!(writer.get_ref().written, b"first\r\nsecond\r\n");
+            Ok(())
+        })
+    }
+}
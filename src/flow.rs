@@ -1,13 +1,55 @@
 use futures_io::{AsyncRead, AsyncWrite};
+use futures_util::future::poll_fn;
 use futures_util::io::{AsyncReadExt, AsyncWriteExt};
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::io::{Error, ErrorKind, Result};
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll};
 
-use crate::http::HeaderMap;
+use crate::authority::Authority;
+use crate::http::{HeaderMap, HeaderName, HeaderValue};
+use ::http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
 
+pub mod body;
 mod handshake_outcome;
-mod request;
+mod proxy_error;
+pub mod request;
+pub mod response;
+pub mod timeout;
+#[cfg(feature = "tokio")]
+pub mod uninit;
 
 pub use handshake_outcome::{HandshakeOutcome, ResponseParts};
+pub use proxy_error::ProxyError;
+pub use response::ResponseParser;
+
+/// The request and response tweaks for the whole handshake. The defaults
+/// match [`handshake`].
+#[derive(Debug, Clone, Default)]
+#[non_exhaustive]
+pub struct HandshakeConfig {
+    pub request: request::Config,
+    pub response: response::Config,
+    /// Keep the exact bytes of the sent request on the
+    /// [`Outcome`](crate::Outcome), e.g. for logging or auditing.
+    pub capture_request: bool,
+    /// Decides whether the response established the tunnel, in place of the
+    /// default 2xx check, e.g. to look at a nonstandard reason phrase.
+    pub success: Option<fn(&ResponseParts) -> bool>,
+}
+
+impl HandshakeConfig {
+    /// Whether the response counts as a success, as per
+    /// [`success`](Self::success).
+    pub fn is_success(&self, response_parts: &ResponseParts) -> bool {
+        match self.success {
+            Some(success) => success(response_parts),
+            None => response_parts.is_tunnel_established(),
+        }
+    }
+}
 
 pub async fn handshake<ARW>(
     stream: &mut ARW,
@@ -19,202 +61,2683 @@ pub async fn handshake<ARW>(
 where
     ARW: AsyncRead + AsyncWrite + Unpin,
 {
-    send_request(stream, host, port, request_headers).await?;
+    let config = HandshakeConfig::default();
+    handshake_with_config(stream, &config, host, port, request_headers, read_buf).await
+}
+
+/// Like [`handshake`], but to the already validated `authority`.
+pub async fn handshake_to<ARW>(
+    stream: &mut ARW,
+    authority: &Authority,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    handshake(
+        stream,
+        authority.host(),
+        authority.port(),
+        request_headers,
+        read_buf,
+    )
+    .await
+}
+
+pub async fn handshake_with_config<ARW>(
+    stream: &mut ARW,
+    config: &HandshakeConfig,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    send_request_with_config(stream, &config.request, host, port, request_headers).await?;
+    receive_response_with_config(stream, &config.response, read_buf).await
+}
+
+/// Like [`handshake_with_config`], but allocates the read buffer itself,
+/// sized as per the [`response::Config::read_buf_len`].
+pub async fn handshake_sized<ARW>(
+    stream: &mut ARW,
+    config: &HandshakeConfig,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+) -> Result<HandshakeOutcome>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut read_buf = vec![0u8; config.response.read_buf_len];
+    handshake_with_config(stream, config, host, port, request_headers, &mut read_buf).await
+}
+
+/// A stream that can be both read and written, for use as a trait object.
+pub trait AsyncReadWrite: AsyncRead + AsyncWrite + Unpin {}
+
+impl<T> AsyncReadWrite for T where T: AsyncRead + AsyncWrite + Unpin + ?Sized {}
+
+/// Like [`handshake`], but over a trait object, so that it's compiled just
+/// once for all the stream types.
+pub async fn handshake_dyn(
+    mut stream: &mut dyn AsyncReadWrite,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome> {
+    handshake(&mut stream, host, port, request_headers, read_buf).await
+}
+
+/// Like [`handshake`], but sends the `request` bytes as is, e.g. for a
+/// hand-crafted request that the [`request::Config`] can't express.
+pub async fn handshake_raw<ARW>(
+    stream: &mut ARW,
+    request: &[u8],
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    write_flushed(stream, request).await?;
     receive_response(stream, read_buf).await
 }
 
-pub async fn send_request<AW>(
-    stream: &mut AW,
-    host: &str,
-    port: u16,
-    headers: &HeaderMap,
-) -> Result<()>
-where
-    AW: AsyncWrite + Unpin,
-{
-    let mut buf: Vec<u8> = Vec::with_capacity(1024);
-    request::write(&mut buf, host, port, headers)?;
-    stream.write_all(buf.as_slice()).await
-}
+/// Like [`handshake`], but for the streams that are already split into
+/// independent write and read halves.
+pub async fn handshake_split<AW, AR>(
+    write_half: &mut AW,
+    read_half: &mut AR,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome>
+where
+    AW: AsyncWrite + Unpin,
+    AR: AsyncRead + Unpin,
+{
+    send_request(write_half, host, port, request_headers).await?;
+    receive_response(read_half, read_buf).await
+}
+
+pub async fn send_request<AW>(
+    stream: &mut AW,
+    host: &str,
+    port: u16,
+    headers: &HeaderMap,
+) -> Result<()>
+where
+    AW: AsyncWrite + Unpin,
+{
+    send_request_with_config(stream, &request::Config::default(), host, port, headers).await
+}
+
+pub async fn send_request_with_config<AW>(
+    stream: &mut AW,
+    config: &request::Config,
+    host: &str,
+    port: u16,
+    headers: &HeaderMap,
+) -> Result<()>
+where
+    AW: AsyncWrite + Unpin,
+{
+    send_request_captured(stream, config, host, port, headers).await?;
+    Ok(())
+}
+
+/// Like [`send_request_with_config`], but returns the exact bytes sent.
+pub async fn send_request_captured<AW>(
+    stream: &mut AW,
+    config: &request::Config,
+    host: &str,
+    port: u16,
+    headers: &HeaderMap,
+) -> Result<Vec<u8>>
+where
+    AW: AsyncWrite + Unpin,
+{
+    let capacity = config
+        .write_buf_capacity
+        .unwrap_or_else(|| request::capacity_hint(host, headers));
+    let mut buf: Vec<u8> = Vec::with_capacity(capacity);
+    request::write_with_config(&mut buf, config, host, port, headers)?;
+    sending(host, port, &buf);
+    write_flushed(stream, &buf).await?;
+    Ok(buf)
+}
+
+/// Like [`send_request_with_config`], but sends the `body` after the head,
+/// for methods other than `CONNECT`. See [`request::write_with_body`].
+pub async fn send_request_with_body<AW>(
+    stream: &mut AW,
+    config: &request::Config,
+    host: &str,
+    port: u16,
+    headers: &HeaderMap,
+    body: &[u8],
+) -> Result<()>
+where
+    AW: AsyncWrite + Unpin,
+{
+    let capacity = config
+        .write_buf_capacity
+        .unwrap_or_else(|| request::capacity_hint(host, headers) + body.len());
+    let mut buf: Vec<u8> = Vec::with_capacity(capacity);
+    request::write_with_body(&mut buf, config, host, port, headers, body)?;
+    sending(host, port, &buf);
+    write_flushed(stream, &buf).await
+}
+
+/// Like [`send_request`], but emits the headers exactly in the supplied order,
+/// for proxies that care about it.
+pub async fn send_request_ordered<AW>(
+    stream: &mut AW,
+    host: &str,
+    port: u16,
+    headers: &[(HeaderName, HeaderValue)],
+) -> Result<()>
+where
+    AW: AsyncWrite + Unpin,
+{
+    let hint = request::capacity_hint(host, headers.iter().map(|(k, v)| (k, v)));
+    let mut buf: Vec<u8> = Vec::with_capacity(hint);
+    request::write_ordered(&mut buf, host, port, headers)?;
+    sending(host, port, &buf);
+    write_flushed(stream, &buf).await
+}
+
+/// Writes the whole request and flushes it, so that it's on the wire, and
+/// not stuck in some buffer, by the time the response is awaited.
+async fn write_flushed<AW>(stream: &mut AW, buf: &[u8]) -> Result<()>
+where
+    AW: AsyncWrite + Unpin,
+{
+    stream.write_all(buf).await?;
+    stream.flush().await
+}
+
+pub async fn receive_response<AR>(stream: &mut AR, read_buf: &mut [u8]) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+{
+    receive_response_with_config(stream, &response::Config::default(), read_buf).await
+}
+
+pub async fn receive_response_with_config<AR>(
+    stream: &mut AR,
+    config: &response::Config,
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+{
+    let mut reader = SliceReader::new(stream, read_buf);
+    let outcome = receive(&mut reader, config, Reading::new(config), &[]).await?;
+    check_received(outcome, config)
+}
+
+/// Applies the checks of the `config` that need the whole response.
+fn check_received(
+    outcome: HandshakeOutcome,
+    config: &response::Config,
+) -> Result<HandshakeOutcome> {
+    if !config.allow_early_data && outcome.has_trailing_data() {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "{} bytes of data arrived right after the response head",
+                outcome.data_after_handshake.len()
+            ),
+        ));
+    }
+    if config.reject_success_body && announces_success_body(&outcome.response_parts) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "the successful response announces a body",
+        ));
+    }
+    Ok(outcome)
+}
+
+fn announces_success_body(response_parts: &ResponseParts) -> bool {
+    let headers = &response_parts.headers;
+    let has_content = headers
+        .get_all(CONTENT_LENGTH)
+        .iter()
+        .any(|value| value.to_str().map_or(true, |value| value.trim() != "0"));
+    response_parts.is_tunnel_established()
+        && (has_content || headers.contains_key(TRANSFER_ENCODING))
+}
+
+/// Like [`receive_response`], but parses the response head with a custom
+/// `parser`.
+pub async fn receive_response_with_parser<AR, P>(
+    stream: &mut AR,
+    parser: &P,
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+    P: ResponseParser + ?Sized,
+{
+    let reading = Reading::new(&response::Config::default());
+    receive(
+        &mut SliceReader::new(stream, read_buf),
+        parser,
+        reading,
+        &[],
+    )
+    .await
+}
+
+/// Like [`receive_response`], but also passes every response header to
+/// `visit` as is, in the order they came in, e.g. to inspect the `Via` entries
+/// that the header map doesn't keep the order of.
+///
+/// This isn't incremental: the headers are only visited once the whole head
+/// is received and parsed, as `httparse` can't report them any earlier.
+pub async fn receive_response_with_visitor<AR, F>(
+    stream: &mut AR,
+    read_buf: &mut [u8],
+    visit: F,
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+    F: FnMut(&str, &[u8]),
+{
+    let parser = Visiting {
+        config: response::Config::default(),
+        visit: RefCell::new(visit),
+    };
+    receive_response_with_parser(stream, &parser, read_buf).await
+}
+
+/// The default parser that also passes the headers to `visit`.
+struct Visiting<F> {
+    config: response::Config,
+    visit: RefCell<F>,
+}
+
+impl<F> ResponseParser for Visiting<F>
+where
+    F: FnMut(&str, &[u8]),
+{
+    fn parse(&self, buf: &[u8]) -> Result<Option<(ResponseParts, usize)>> {
+        let mut visit = self.visit.borrow_mut();
+        parse_head_visiting(buf, &self.config, &mut *visit, &mut HeaderMap::new())
+    }
+}
+
+/// Like [`receive_response`], but fills in the caller's `response_parts`,
+/// reusing the allocation of their headers, e.g. across the handshakes on
+/// pooled connections. Returns the data after the response head.
+///
+/// On error, `response_parts` is left as it was, except that the headers are
+/// cleared if an interim `1xx` response came in before the error.
+pub async fn receive_response_into<AR>(
+    stream: &mut AR,
+    response_parts: &mut ResponseParts,
+    read_buf: &mut [u8],
+) -> Result<Vec<u8>>
+where
+    AR: AsyncRead + Unpin,
+{
+    let parser = Reusing {
+        config: response::Config::default(),
+        headers: RefCell::new(mem::take(&mut response_parts.headers)),
+    };
+    match receive_response_with_parser(stream, &parser, read_buf).await {
+        Ok(outcome) => {
+            *response_parts = outcome.response_parts;
+            Ok(outcome.data_after_handshake)
+        }
+        Err(err) => {
+            response_parts.headers = parser.headers.into_inner();
+            Err(err)
+        }
+    }
+}
+
+/// The default parser that fills in the given headers. They're only kept by
+/// the final response; the interim ones hand them back.
+struct Reusing {
+    config: response::Config,
+    headers: RefCell<HeaderMap>,
+}
+
+impl ResponseParser for Reusing {
+    fn parse(&self, buf: &[u8]) -> Result<Option<(ResponseParts, usize)>> {
+        let mut headers = self.headers.borrow_mut();
+        let mut parsed = parse_head_visiting(buf, &self.config, &mut |_, _| {}, &mut headers)?;
+        if let Some((parts, _)) = &mut parsed {
+            if is_interim(parts.status_code) {
+                mem::swap(&mut *headers, &mut parts.headers);
+                headers.clear();
+            }
+        }
+        Ok(parsed)
+    }
+}
+
+/// Like [`receive_response`], but seeds the parser with the bytes that were
+/// already read off the stream by the caller. Non-empty `prefill` is treated
+/// as if it was the result of the first read.
+pub async fn receive_response_with_prefill<AR>(
+    stream: &mut AR,
+    prefill: &[u8],
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+{
+    let config = response::Config::default();
+    let mut reader = SliceReader::new(stream, read_buf);
+    receive(&mut reader, &config, Reading::new(&config), prefill).await
+}
+
+/// How the response is read, as opposed to how it's parsed.
+#[derive(Debug, Clone, Copy)]
+struct Reading {
+    min_read_buf_len: usize,
+    max_status_line_len: usize,
+    force_carry_on: bool,
+}
+
+impl Reading {
+    fn new(config: &response::Config) -> Self {
+        // The status line is `HTTP/1.1 200 `, then the reason phrase, and then
+        // the line ending.
+        const STATUS_LINE_OVERHEAD: usize = b"HTTP/1.1 200 \r\n".len();
+        Self {
+            min_read_buf_len: config.min_read_buf_len,
+            max_status_line_len: config.max_reason_len.saturating_add(STATUS_LINE_OVERHEAD),
+            force_carry_on: config.force_carry_on,
+        }
+    }
+
+    /// Fails once the incomplete status line at the start of `buf` is longer
+    /// than the longest reason phrase allows, so that the carry-on buffer
+    /// doesn't grow unbounded before it's ever parsed.
+    fn check_status_line(&self, buf: &[u8]) -> Result<()> {
+        if buf.len() > self.max_status_line_len && !buf.contains(&b'\n') {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "status line exceeds the maximum of {} bytes",
+                    self.max_status_line_len
+                ),
+            ));
+        }
+        Ok(())
+    }
+}
+
+/// The stream along with the buffer that the response head is read into, one
+/// part at a time.
+trait HeadReader {
+    /// The size of the buffer.
+    fn buf_len(&self) -> usize;
+
+    /// Reads into the buffer once, retrying the interrupted reads, and returns
+    /// the amount of data read.
+    fn poll_read_part(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>>;
+
+    /// The first `len` bytes of the data from the last read.
+    fn part(&self, len: usize) -> &[u8];
+}
+
+/// Reads from a `futures` stream into an initialized buffer.
+struct SliceReader<'a, AR> {
+    stream: &'a mut AR,
+    buf: &'a mut [u8],
+}
+
+impl<'a, AR> SliceReader<'a, AR> {
+    fn new(stream: &'a mut AR, buf: &'a mut [u8]) -> Self {
+        Self { stream, buf }
+    }
+}
+
+impl<AR> HeadReader for SliceReader<'_, AR>
+where
+    AR: AsyncRead + Unpin,
+{
+    fn buf_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    fn poll_read_part(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        loop {
+            match Pin::new(&mut *self.stream).poll_read(cx, self.buf) {
+                Poll::Ready(Err(err)) if err.kind() == ErrorKind::Interrupted => continue,
+                poll => return poll,
+            }
+        }
+    }
+
+    fn part(&self, len: usize) -> &[u8] {
+        &self.buf[..len]
+    }
+}
+
+/// Receives the final response, skipping over the interim `1xx` ones, but
+/// `101 Switching Protocols`, which is final.
+async fn receive<R, P>(
+    reader: &mut R,
+    parser: &P,
+    reading: Reading,
+    prefill: &[u8],
+) -> Result<HandshakeOutcome>
+where
+    R: HeadReader + ?Sized,
+    P: ResponseParser + ?Sized,
+{
+    let (outcome, _) = receive_with_head(reader, parser, reading, prefill).await?;
+    Ok(outcome)
+}
+
+/// Where the raw response head ended up.
+enum Head {
+    /// At the start of the last read's data, up to the given length.
+    ReadBuf(usize),
+    /// At the start of the prefill, up to the given length.
+    Prefill(usize),
+    /// In the carry-on buffer, that holds just the head.
+    CarryOn(Vec<u8>),
+}
+
+/// Like [`receive`], but also tells where the raw head of the final response
+/// is.
+async fn receive_with_head<R, P>(
+    reader: &mut R,
+    parser: &P,
+    reading: Reading,
+    prefill: &[u8],
+) -> Result<(HandshakeOutcome, Head)>
+where
+    R: HeadReader + ?Sized,
+    P: ResponseParser + ?Sized,
+{
+    let (mut outcome, mut head) = receive_one(reader, parser, reading, prefill).await?;
+    while is_interim(outcome.response_parts.status_code) {
+        // The data after the interim response is the beginning of the next one.
+        let leftover = std::mem::take(&mut outcome.data_after_handshake);
+        let (next, next_head) = receive_one(reader, parser, reading, &leftover).await?;
+        outcome = next;
+        head = match next_head {
+            // The leftover is gone after this iteration.
+            Head::Prefill(len) => Head::CarryOn(leftover[..len].to_vec()),
+            next_head => next_head,
+        };
+    }
+    Ok((outcome, head))
+}
+
+/// Like [`receive_response`], but also returns the raw response head. In the
+/// happy path, when the head comes in with the first read, it's borrowed from
+/// the `read_buf`, and it's only copied if it took more reads.
+pub async fn receive_response_raw<'buf, AR>(
+    stream: &mut AR,
+    read_buf: &'buf mut [u8],
+) -> Result<(HandshakeOutcome, Cow<'buf, [u8]>)>
+where
+    AR: AsyncRead + Unpin,
+{
+    let config = response::Config::default();
+    let mut reader = SliceReader::new(stream, &mut *read_buf);
+    let (outcome, head) =
+        receive_with_head(&mut reader, &config, Reading::new(&config), &[]).await?;
+    let head = match head {
+        Head::ReadBuf(len) => Cow::Borrowed(&read_buf[..len]),
+        Head::Prefill(_) => unreachable!("there's no prefill"),
+        Head::CarryOn(buf) => Cow::Owned(buf),
+    };
+    Ok((outcome, head))
+}
+
+fn is_interim(status_code: u16) -> bool {
+    (100..200).contains(&status_code) && status_code != 101
+}
+
+async fn receive_one<R, P>(
+    reader: &mut R,
+    parser: &P,
+    reading: Reading,
+    prefill: &[u8],
+) -> Result<(HandshakeOutcome, Head)>
+where
+    R: HeadReader + ?Sized,
+    P: ResponseParser + ?Sized,
+{
+    if reader.buf_len() < reading.min_read_buf_len {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!(
+                "read buffer of {} bytes is smaller than the minimum of {} bytes",
+                reader.buf_len(),
+                reading.min_read_buf_len
+            ),
+        ));
+    }
+
+    // Happy path - we expect the response to be reasonably small and to come in
+    // complete as a single buffer via a single read.
+    // In this case we don't need to allocate and carry-on second buffer.
+
+    // TODO: allow user to customize the data structure used for a carry-on
+    // buffer. This is useful in case user wants to limit the amount of memory
+    // this buffer can grow to, or for the cases when a more optimized data
+    // structure is at hand.
+    let mut carry_on_buf = {
+        let first_buf = if prefill.is_empty() {
+            let total = read_head_part(reader).await?;
+            reader.part(total)
+        } else {
+            prefill
+        };
+
+        if !reading.force_carry_on {
+            if let Some(outcome) = try_parse(first_buf, parser)? {
+                let len = first_buf.len() - outcome.data_after_handshake.len();
+                let head = if prefill.is_empty() {
+                    Head::ReadBuf(len)
+                } else {
+                    Head::Prefill(len)
+                };
+                return Ok((received(outcome, false), head));
+            }
+        }
+
+        // We didn't exit early on error or completion, this means we're at
+        // slower path and we need a carry-on buffer.
+        // The first read data is copied over right away, so that the borrow of
+        // the buffer ends here, before it's reused for the subsequent reads.
+        Vec::from(first_buf)
+    };
+    reading.check_status_line(&carry_on_buf)?;
+    // Once there's a line ending, the status line is complete, and the
+    // reason phrase is checked by the parser.
+    let mut status_line_done = carry_on_buf.contains(&b'\n');
+    // When forced onto this path, the first read wasn't parsed yet.
+    let mut parse_due = reading.force_carry_on;
+    loop {
+        if parse_due {
+            if let Some(outcome) = try_parse(carry_on_buf.as_slice(), parser)? {
+                carry_on_buf.truncate(carry_on_buf.len() - outcome.data_after_handshake.len());
+                return Ok((received(outcome, true), Head::CarryOn(carry_on_buf)));
+            }
+        }
+
+        let total = read_head_part(reader).await?;
+        let buf = reader.part(total);
+
+        // `httparse` can't resume parsing, so every attempt reparses the whole
+        // carry-on buffer. Only attempt it when the newly arrived data might
+        // have completed the response head.
+        let scan_from = carry_on_buf.len().saturating_sub(2);
+        carry_on_buf.extend_from_slice(buf);
+        if !status_line_done {
+            reading.check_status_line(&carry_on_buf)?;
+            status_line_done = buf.contains(&b'\n');
+        }
+        parse_due = contains_head_end(&carry_on_buf[scan_from..]);
+    }
+}
+
+/// Like [`receive_response`], but reads into the spare capacity of `read_buf`,
+/// growing it as needed, instead of using a separate carry-on buffer.
+///
+/// After the call, `read_buf` holds all the data that was read, including the
+/// response heads, and can be reused to avoid allocations.
+pub async fn receive_response_vec<AR>(
+    stream: &mut AR,
+    read_buf: &mut Vec<u8>,
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+{
+    receive_response_vec_with_config(stream, &response::Config::default(), read_buf).await
+}
+
+/// Like [`receive_response_vec`], but with the response tweaks. The spare
+/// capacity that is read into is never smaller than the
+/// [`min_read_buf_len`](response::Config::min_read_buf_len).
+pub async fn receive_response_vec_with_config<AR>(
+    stream: &mut AR,
+    config: &response::Config,
+    read_buf: &mut Vec<u8>,
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+{
+    const MIN_GROW: usize = 512;
+
+    let reading = Reading::new(config);
+    read_buf.clear();
+    // Where the response being received starts, past the interim ones.
+    let mut start = 0;
+    let mut status_line_done = false;
+    let mut parse_due = false;
+    let mut slow_path = false;
+    loop {
+        if parse_due {
+            match try_parse(&read_buf[start..], config)? {
+                Some(outcome) if is_interim(outcome.response_parts.status_code) => {
+                    // The data after the interim response is the beginning of
+                    // the next one, so it's parsed right away.
+                    start = read_buf.len() - outcome.data_after_handshake.len();
+                    reading.check_status_line(&read_buf[start..])?;
+                    status_line_done = read_buf[start..].contains(&b'\n');
+                    parse_due = start != read_buf.len();
+                    continue;
+                }
+                Some(outcome) => return check_received(received(outcome, slow_path), config),
+                None => {}
+            }
+        }
+
+        let spare = read_buf.capacity() - read_buf.len();
+        if spare < reading.min_read_buf_len.max(1) {
+            let additional = read_buf
+                .capacity()
+                .max(MIN_GROW)
+                .max(reading.min_read_buf_len);
+            read_buf.reserve(additional);
+        }
+
+        let filled = read_buf.len();
+        read_buf.resize(read_buf.capacity(), 0);
+        let total = match read_retrying(stream, &mut read_buf[filled..]).await {
+            Ok(total) => total,
+            Err(err) => {
+                read_buf.truncate(filled);
+                return Err(err);
+            }
+        };
+        read_buf.truncate(filled + total);
+        if total == 0 {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "stream ended before the response head was complete",
+            ));
+        }
+        slow_path = filled != 0;
+
+        if !status_line_done {
+            reading.check_status_line(&read_buf[start..])?;
+            status_line_done = read_buf[filled..].contains(&b'\n');
+        }
+        // Always parse the first read of a response to fail early on garbage,
+        // and then reparse only if the newly arrived data might've completed
+        // the head.
+        let scan_from = filled.saturating_sub(2).max(start);
+        parse_due = filled == start || contains_head_end(&read_buf[scan_from..]);
+    }
+}
+
+/// Like [`ensure_success`], but without reading any further: the error gets
+/// up to `snippet_len` bytes of whatever arrived after the response head, as
+/// is, which usually is the beginning of the error page.
+pub fn check_success(outcome: HandshakeOutcome, snippet_len: usize) -> Result<HandshakeOutcome> {
+    if outcome.response_parts.is_tunnel_established() {
+        return Ok(outcome);
+    }
+
+    let mut snippet = outcome.data_after_handshake;
+    snippet.truncate(snippet_len);
+    Err(ProxyError {
+        response_parts: outcome.response_parts,
+        body: Some(snippet),
+    }
+    .into())
+}
+
+/// Turns a non-2xx response into a [`ProxyError`]. If `body_limit` is set, the
+/// response body, up to that many bytes, is read and decoded into the error
+/// for diagnostics. For a custom success check, see
+/// [`HandshakeConfig::is_success`].
+pub async fn ensure_success<AR>(
+    stream: &mut AR,
+    outcome: HandshakeOutcome,
+    body_limit: Option<usize>,
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+{
+    if outcome.response_parts.is_tunnel_established() {
+        return Ok(outcome);
+    }
+
+    let body = match body_limit {
+        Some(limit) => Some(
+            body::read_body(
+                stream,
+                &outcome.response_parts.headers,
+                &outcome.data_after_handshake,
+                limit,
+            )
+            .await?,
+        ),
+        None => None,
+    };
+    Err(ProxyError {
+        response_parts: outcome.response_parts,
+        body,
+    }
+    .into())
+}
+
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn sending(host: &str, port: u16, request: &[u8]) {
+    debug_event!(
+        authority = %format_args!("{}:{}", host, port),
+        bytes = request.len(),
+        "sending the request"
+    );
+}
+
+/// `slow_path` tells whether the response took more than a single read.
+#[cfg_attr(not(feature = "tracing"), allow(unused_variables))]
+fn received(mut outcome: HandshakeOutcome, slow_path: bool) -> HandshakeOutcome {
+    outcome.slow_path = slow_path;
+    debug_event!(
+        status_code = outcome.response_parts.status_code,
+        slow_path,
+        trailing_bytes = outcome.data_after_handshake.len(),
+        "received the response"
+    );
+    outcome
+}
+
+/// Reads from the `stream`, retrying the reads that were interrupted.
+async fn read_retrying<AR>(stream: &mut AR, buf: &mut [u8]) -> Result<usize>
+where
+    AR: AsyncRead + Unpin,
+{
+    loop {
+        match stream.read(buf).await {
+            Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Reads a part of the response head, which is never complete at the end of
+/// the stream, so that's `UnexpectedEof`.
+async fn read_head_part<R>(reader: &mut R) -> Result<usize>
+where
+    R: HeadReader + ?Sized,
+{
+    match poll_fn(|cx| reader.poll_read_part(cx)).await? {
+        0 => Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "stream ended before the response head was complete",
+        )),
+        total => Ok(total),
+    }
+}
+
+/// Cheaply checks whether `buf` contains an empty line, which is a
+/// prerequisite for the response head to be complete.
+fn contains_head_end(buf: &[u8]) -> bool {
+    buf.iter().enumerate().any(|(pos, &byte)| {
+        byte == b'\n' && matches!(&buf[pos + 1..], [b'\n', ..] | [b'\r', b'\n', ..])
+    })
+}
+
+/// Parses a complete response head from `buf`, returning its parts along
+/// with the number of bytes it took up. Whatever follows the head is left
+/// alone. An incomplete head is reported as `UnexpectedEof`.
+pub fn parse_response(buf: &[u8]) -> Result<(ResponseParts, usize)> {
+    parse_head(buf, &response::Config::default())?
+        .ok_or_else(|| Error::new(ErrorKind::UnexpectedEof, "the response head is incomplete"))
+}
+
+/// Parses a raw header block, e.g. from a template, into the headers for the
+/// request. The block ends with an empty line, and an incomplete one is
+/// reported as `UnexpectedEof`.
+pub fn parse_request_headers(buf: &[u8]) -> Result<HeaderMap> {
+    let mut scratch = [httparse::EMPTY_HEADER; response::MAX_HEADERS];
+    let parsed = httparse::parse_headers(buf, &mut scratch).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "header block has more than {} headers",
+                response::MAX_HEADERS
+            ),
+        ),
+        err => Error::new(ErrorKind::InvalidData, err),
+    })?;
+    let headers = match parsed {
+        httparse::Status::Complete((_, headers)) => headers,
+        httparse::Status::Partial => {
+            return Err(Error::new(
+                ErrorKind::UnexpectedEof,
+                "the header block is incomplete",
+            ))
+        }
+    };
+
+    let mut map = HeaderMap::with_capacity(headers.len());
+    for header in headers {
+        let name = HeaderName::from_bytes(header.name.as_bytes())
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        let value = HeaderValue::from_bytes(header.value)
+            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+        map.append(name, value);
+    }
+    Ok(map)
+}
+
+/// Parses the response head from `buf`, returning `None` if more data is
+/// needed to complete it.
+fn try_parse<P>(buf: &[u8], parser: &P) -> Result<Option<HandshakeOutcome>>
+where
+    P: ResponseParser + ?Sized,
+{
+    Ok(parser
+        .parse(buf)?
+        .map(|(response_parts, consumed)| HandshakeOutcome {
+            response_parts,
+            data_after_handshake: Vec::from(&buf[consumed..]),
+            slow_path: false,
+        }))
+}
+
+fn parse_head(buf: &[u8], config: &response::Config) -> Result<Option<(ResponseParts, usize)>> {
+    parse_head_visiting(buf, config, &mut |_, _| {}, &mut HeaderMap::new())
+}
+
+/// Like [`parse_head`], but passes every header of the complete head to
+/// `visit`, in the order they came in, and takes the `headers` to fill in.
+fn parse_head_visiting(
+    buf: &[u8],
+    config: &response::Config,
+    visit: &mut dyn FnMut(&str, &[u8]),
+    headers: &mut HeaderMap,
+) -> Result<Option<(ResponseParts, usize)>> {
+    if config.reject_obs_fold && has_obs_fold(buf) {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "response header uses obsolete line folding",
+        ));
+    }
+
+    let mut response_headers = [httparse::EMPTY_HEADER; response::MAX_HEADERS];
+    let mut response = httparse::Response::new(&mut response_headers);
+
+    let status = response.parse(buf).map_err(|err| match err {
+        httparse::Error::TooManyHeaders => Error::new(
+            ErrorKind::InvalidData,
+            format!("response has more than {} headers", response::MAX_HEADERS),
+        ),
+        err => Error::new(ErrorKind::InvalidData, err),
+    })?;
+    match status {
+        httparse::Status::Partial => Ok(None),
+        httparse::Status::Complete(consumed) => Ok(Some({
+            if !config.allow_bare_lf && has_bare_lf(&buf[..consumed]) {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "response head has a line ending with a bare LF",
+                ));
+            }
+            let reason_len = response.reason.map_or(0, str::len);
+            if reason_len > config.max_reason_len {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!(
+                        "reason phrase of {} bytes exceeds the maximum of {} bytes",
+                        reason_len, config.max_reason_len
+                    ),
+                ));
+            }
+            for header in response.headers.iter() {
+                visit(header.name, header.value);
+            }
+            (
+                handshake_outcome::parts_from_complete_response(response, mem::take(headers)),
+                consumed,
+            )
+        })),
+    }
+}
+
+/// Checks whether any line in `buf` ends with a `\n` that isn't preceded by
+/// a `\r`.
+fn has_bare_lf(buf: &[u8]) -> bool {
+    buf.iter()
+        .enumerate()
+        .any(|(pos, &byte)| byte == b'\n' && (pos == 0 || buf[pos - 1] != b'\r'))
+}
+
+/// Checks whether any line of the head in `buf`, up to the empty line that
+/// ends it, starts with whitespace, continuing the previous header.
+fn has_obs_fold(buf: &[u8]) -> bool {
+    // The status line can't be a continuation.
+    for line in buf.split(|&b| b == b'\n').skip(1) {
+        match line.first() {
+            Some(b' ') | Some(b'\t') => return true,
+            None | Some(b'\r') => return false,
+            Some(_) => {}
+        }
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{executor, io::Cursor};
+
+    /// A reader that returns at most `chunk` bytes per read.
+    struct TrickleReader {
+        data: &'static [u8],
+        chunk: usize,
+    }
+
+    impl AsyncRead for TrickleReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize>> {
+            let n = buf.len().min(self.chunk).min(self.data.len());
+            buf[..n].copy_from_slice(&self.data[..n]);
+            self.data = &self.data[n..];
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    /// The default parser that counts the parse attempts.
+    #[derive(Default)]
+    struct CountingParser {
+        config: response::Config,
+        calls: std::cell::Cell<usize>,
+    }
+
+    impl ResponseParser for CountingParser {
+        fn parse(&self, buf: &[u8]) -> Result<Option<(ResponseParts, usize)>> {
+            self.calls.set(self.calls.get() + 1);
+            self.config.parse(buf)
+        }
+    }
+
+    /// A reader that fails with `Interrupted` every other read.
+    struct InterruptingReader<R> {
+        inner: R,
+        interrupt: bool,
+    }
+
+    impl<R: AsyncRead + Unpin> AsyncRead for InterruptingReader<R> {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize>> {
+            self.interrupt = !self.interrupt;
+            if self.interrupt {
+                return Poll::Ready(Err(ErrorKind::Interrupted.into()));
+            }
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    /// A reader that returns the scripted chunks one read at a time.
+    struct ChunksReader {
+        chunks: std::collections::VecDeque<&'static [u8]>,
+    }
+
+    impl AsyncRead for ChunksReader {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize>> {
+            let chunk = match self.chunks.pop_front() {
+                Some(chunk) => chunk,
+                None => return Poll::Ready(Ok(0)),
+            };
+            let n = buf.len().min(chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            if n < chunk.len() {
+                self.chunks.push_front(&chunk[n..]);
+            }
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    #[test]
+    fn handshake_sized_buffers() -> Result<()> {
+        executor::block_on(async {
+            let response = "HTTP/1.1 200 Connection established\r\n\r\n";
+            let mut headers = HeaderMap::new();
+            headers.insert("proxy-agent", HeaderValue::from_static("test"));
+
+            let mut config = HandshakeConfig::default();
+            config.request.write_buf_capacity = Some(1);
+            let mut socket = merge_io::MergeIO::new(Cursor::new(response), Cursor::new(vec![]));
+            let outcome =
+                handshake_sized(&mut socket, &config, "127.0.0.1", 8080, &headers).await?;
+            assert!(!outcome.slow_path);
+            // The write buffer grew to fit the whole request.
+            let mut expected = Vec::new();
+            request::write(&mut expected, "127.0.0.1", 8080, &headers)?;
+            assert_eq!(socket.writer().get_ref(), &expected);
+
+            config.response.read_buf_len = 16;
+            let mut socket = merge_io::MergeIO::new(Cursor::new(response), Cursor::new(vec![]));
+            let outcome =
+                handshake_sized(&mut socket, &config, "127.0.0.1", 8080, &headers).await?;
+            assert!(outcome.slow_path);
+            assert_eq!(outcome.response_parts.status_code, 200);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn handshake_raw_test() -> Result<()> {
+        executor::block_on(async {
+            let request = b"CONNECT example.com:443 HTTP/1.0\r\nX-Custom:  spaced\r\n\r\n";
+            let reader = Cursor::new("HTTP/1.0 200 OK\r\n\r\ndata");
+            let mut socket = merge_io::MergeIO::new(reader, Cursor::new(vec![]));
+            let mut read_buf = [0u8; 1024];
+            let outcome = handshake_raw(&mut socket, request, &mut read_buf).await?;
+            assert_eq!(socket.writer().get_ref().as_slice(), &request[..]);
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"data");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn handshake_dyn_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\ndata");
+            let mut stream: Box<dyn AsyncReadWrite> =
+                Box::new(merge_io::MergeIO::new(reader, Cursor::new(vec![])));
+            let mut read_buf = [0u8; 1024];
+            let outcome = handshake_dyn(
+                &mut *stream,
+                "127.0.0.1",
+                8080,
+                &HeaderMap::new(),
+                &mut read_buf,
+            )
+            .await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"data");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn handshake_split_test() -> Result<()> {
+        executor::block_on(async {
+            let expected_req = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                                Host: 127.0.0.1:8080\r\n\
+                                \r\n";
+            let mut writer = Cursor::new(vec![0u8; 1024]);
+            let mut reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\nbody");
+            let mut read_buf = [0u8; 1024];
+            let outcome = handshake_split(
+                &mut writer,
+                &mut reader,
+                "127.0.0.1",
+                8080,
+                &HeaderMap::new(),
+                &mut read_buf,
+            )
+            .await?;
+
+            assert_eq!(
+                &writer.get_ref()[..writer.position() as usize],
+                expected_req.as_bytes(),
+            );
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"body");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn send_request_without_headers() -> Result<()> {
+        executor::block_on(async {
+            let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                              Host: 127.0.0.1:8080\r\n\
+                              \r\n";
+            let mut socket = Cursor::new(vec![0u8; 1024]);
+            let headers = HeaderMap::new();
+            send_request(&mut socket, "127.0.0.1", 8080, &headers).await?;
+
+            assert_eq!(
+                &socket.get_ref()[..socket.position() as usize],
+                sample_res.as_bytes(),
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn send_request_with_headers() -> Result<()> {
+        executor::block_on(async {
+            let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                              Host: 127.0.0.1:8080\r\n\
+                              proxy-authorization: Basic aGVsbG86d29ybGQ=\r\n\
+                              \r\n";
+            let mut socket = Cursor::new(vec![0u8; 1024]);
+            let mut headers = HeaderMap::new();
+            headers.insert(
+                "Proxy-Authorization",
+                HeaderValue::from_static("Basic aGVsbG86d29ybGQ="),
+            );
+            send_request(&mut socket, "127.0.0.1", 8080, &headers).await?;
+
+            assert_eq!(
+                &socket.get_ref()[..socket.position() as usize],
+                sample_res.as_bytes(),
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn send_request_http_versions() -> Result<()> {
+        executor::block_on(async {
+            let cases = [
+                (
+                    request::Version::Http10,
+                    "CONNECT 127.0.0.1:8080 HTTP/1.0\r\n",
+                ),
+                (
+                    request::Version::Http11,
+                    "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n",
+                ),
+            ];
+            for &(version, request_line) in cases.iter() {
+                let mut socket = Cursor::new(vec![0u8; 1024]);
+                let config = request::Config {
+                    version,
+                    ..Default::default()
+                };
+                send_request_with_config(
+                    &mut socket,
+                    &config,
+                    "127.0.0.1",
+                    8080,
+                    &HeaderMap::new(),
+                )
+                .await?;
+
+                let sample_res = format!("{}Host: 127.0.0.1:8080\r\n\r\n", request_line);
+                assert_eq!(
+                    &socket.get_ref()[..socket.position() as usize],
+                    sample_res.as_bytes(),
+                );
+            }
+            assert_eq!(request::Version::default(), request::Version::Http11);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn send_request_ordered_headers() -> Result<()> {
+        executor::block_on(async {
+            let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                              Host: 127.0.0.1:8080\r\n\
+                              x-second: 2\r\n\
+                              proxy-authorization: Basic aGVsbG86d29ybGQ=\r\n\
+                              x-first: 1\r\n\
+                              \r\n";
+            let mut socket = Cursor::new(vec![0u8; 1024]);
+            let headers = [
+                (
+                    HeaderName::from_static("x-second"),
+                    HeaderValue::from_static("2"),
+                ),
+                (
+                    HeaderName::from_static("proxy-authorization"),
+                    HeaderValue::from_static("Basic aGVsbG86d29ybGQ="),
+                ),
+                (
+                    HeaderName::from_static("x-first"),
+                    HeaderValue::from_static("1"),
+                ),
+            ];
+            send_request_ordered(&mut socket, "127.0.0.1", 8080, &headers).await?;
+
+            assert_eq!(
+                &socket.get_ref()[..socket.position() as usize],
+                sample_res.as_bytes(),
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_test() -> Result<()> {
+        executor::block_on(async {
+            let sample_res = "HTTP/1.1 200 OK\r\n\
+                              \r\n\
+                              this is already the proxied content";
+            let mut socket = Cursor::new(sample_res);
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(
+                outcome.data_after_handshake.as_slice(),
+                "this is already the proxied content".as_bytes()
+            );
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.response_parts.reason_phrase, "OK");
+            assert_eq!(outcome.response_parts.headers.len(), 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_with_headers() -> Result<()> {
+        executor::block_on(async {
+            let sample_res = "HTTP/1.1 200 OK\r\n\
+                              X-Custom: Sample Value\r\n\
+                              \r\n\
+                              this is already the proxied content";
+            let mut socket = Cursor::new(sample_res);
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(
+                outcome.data_after_handshake.as_slice(),
+                "this is already the proxied content".as_bytes()
+            );
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.response_parts.reason_phrase, "OK");
+            assert_eq!(outcome.response_parts.headers.len(), 1);
+            assert_eq!(
+                outcome.response_parts.headers.get("x-custom").unwrap(),
+                &"Sample Value"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_multi_word_reason() -> Result<()> {
+        executor::block_on(async {
+            let sample_res = "HTTP/1.1 200 Connection established\r\n\
+                              \r\n";
+            let mut socket = Cursor::new(sample_res);
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(
+                outcome.response_parts.reason_phrase,
+                "Connection established"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_reason_trailing_whitespace() -> Result<()> {
+        executor::block_on(async {
+            let sample_res = "HTTP/1.1 200 Connection established \t \r\n\
+                              \r\n";
+            let mut socket = Cursor::new(sample_res);
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(
+                outcome.response_parts.reason_phrase,
+                "Connection established"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_with_prefill_test() -> Result<()> {
+        executor::block_on(async {
+            let prefill = "HTTP/1.1 200 OK\r\n\
+                           X-Cus";
+            let sample_res = "tom: Sample Value\r\n\
+                              \r\n\
+                              this is already the proxied content";
+            let mut socket = Cursor::new(sample_res);
+            let mut read_buf = [0u8; 1024];
+            let outcome =
+                receive_response_with_prefill(&mut socket, prefill.as_bytes(), &mut read_buf)
+                    .await?;
+            assert_eq!(
+                outcome.data_after_handshake.as_slice(),
+                "this is already the proxied content".as_bytes()
+            );
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(
+                outcome.response_parts.headers.get("x-custom").unwrap(),
+                &"Sample Value"
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_split_status_line() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = ChunksReader {
+                chunks: vec![&b"HTTP/1.1 2"[..], b"00 OK\r\n\r\nbody"].into(),
+            };
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"body");
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.response_parts.reason_phrase, "OK");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_tiny_first_read() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = ChunksReader {
+                chunks: vec![&b"H"[..], b"TTP/1.1 200 OK\r\n\r\nbody"].into(),
+            };
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"body");
+            assert_eq!(outcome.response_parts.status_code, 200);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_vec_grows() -> Result<()> {
+        executor::block_on(async {
+            let mut sample_res = String::from("HTTP/1.1 200 OK\r\n");
+            for i in 0..10 {
+                sample_res += &format!("X-Custom-{}: {}\r\n", i, "v".repeat(200));
+            }
+            sample_res += "\r\nthis is already the proxied content";
+            let mut socket = Cursor::new(sample_res.as_bytes());
+
+            let mut read_buf = Vec::with_capacity(16);
+            let outcome = receive_response_vec(&mut socket, &mut read_buf).await?;
+
+            assert!(read_buf.capacity() >= sample_res.len());
+            assert_eq!(read_buf.as_slice(), sample_res.as_bytes());
+            assert_eq!(
+                outcome.data_after_handshake.as_slice(),
+                "this is already the proxied content".as_bytes()
+            );
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.response_parts.headers.len(), 10);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_unexpected_eof() {
+        executor::block_on(async {
+            let mut read_buf = [0u8; 1024];
+
+            // The proxy closed the connection right away.
+            let mut socket = Cursor::new("");
+            let err = receive_response(&mut socket, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+            // Or in the middle of the head.
+            let mut socket = TrickleReader {
+                data: b"HTTP/1.1 200 OK\r\n",
+                chunk: 4,
+            };
+            let err = receive_response(&mut socket, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        })
+    }
+
+    #[test]
+    fn receive_response_vec_unexpected_eof() {
+        executor::block_on(async {
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\n");
+            let mut read_buf = Vec::new();
+            let err = receive_response_vec(&mut socket, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        })
+    }
+
+    #[test]
+    fn ensure_success_chunked_error_body() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = ChunksReader {
+                chunks: vec![
+                    &b"HTTP/1.1 502 Bad Gateway\r\n\
+                       Transfer-Encoding: chunked\r\n\
+                       \r\n\
+                       8\r\nupstre"[..],
+                    b"am\r\n",
+                    b"12\r\n is not responding\r\n0\r\n\r\n",
+                ]
+                .into(),
+            };
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            let err = ensure_success(&mut socket, outcome, Some(1024))
+                .await
+                .unwrap_err();
+
+            let proxy_error = ProxyError::from_io(&err).unwrap();
+            assert_eq!(proxy_error.response_parts.status_code, 502);
+            assert_eq!(
+                proxy_error.body.as_deref(),
+                Some(&b"upstream is not responding"[..])
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn ensure_success_passes_2xx() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\n\r\nbody");
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            let outcome = ensure_success(&mut socket, outcome, Some(1024)).await?;
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"body");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn check_success_captures_snippet() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = Cursor::new(
+                "HTTP/1.1 403 Forbidden\r\n\
+                 Content-Type: text/html\r\n\
+                 \r\n\
+                 <html><body>Access denied by policy</body></html>",
+            );
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            let err = check_success(outcome, 32).unwrap_err();
+
+            let proxy_error = ProxyError::from_io(&err).unwrap();
+            assert_eq!(proxy_error.response_parts.status_code, 403);
+            assert_eq!(
+                proxy_error.body_text().as_deref(),
+                Some("<html><body>Access denied by pol")
+            );
+
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\n\r\nbody");
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            let outcome = check_success(outcome, 32)?;
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"body");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_interrupted() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = InterruptingReader {
+                inner: ChunksReader {
+                    chunks: vec![&b"HTTP/1.1 200 OK\r\n"[..], b"\r\nbody"].into(),
+                },
+                interrupt: false,
+            };
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"body");
+            Ok(())
+        })
+    }
+
+    #[cfg(feature = "tracing")]
+    #[test]
+    fn tracing_events() -> Result<()> {
+        use std::sync::{Arc, Mutex};
+        use tracing::{field::Field, span, Event, Metadata};
+
+        /// Captures the fields of every event as a string.
+        struct Capture(Arc<Mutex<Vec<String>>>);
+
+        impl tracing::Subscriber for Capture {
+            fn enabled(&self, _: &Metadata<'_>) -> bool {
+                true
+            }
+            fn new_span(&self, _: &span::Attributes<'_>) -> span::Id {
+                span::Id::from_u64(1)
+            }
+            fn record(&self, _: &span::Id, _: &span::Record<'_>) {}
+            fn record_follows_from(&self, _: &span::Id, _: &span::Id) {}
+            fn event(&self, event: &Event<'_>) {
+                let mut fields = String::new();
+                event.record(&mut |field: &Field, value: &dyn std::fmt::Debug| {
+                    fields += &format!("{}={:?} ", field.name(), value);
+                });
+                self.0.lock().unwrap().push(fields);
+            }
+            fn enter(&self, _: &span::Id) {}
+            fn exit(&self, _: &span::Id) {}
+        }
+
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let subscriber = Capture(events.clone());
+        tracing::subscriber::with_default(subscriber, || {
+            executor::block_on(async {
+                let mut writer = Cursor::new(vec![0u8; 1024]);
+                let mut reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+                let mut read_buf = [0u8; 1024];
+                handshake_split(
+                    &mut writer,
+                    &mut reader,
+                    "127.0.0.1",
+                    8080,
+                    &HeaderMap::new(),
+                    &mut read_buf,
+                )
+                .await
+            })
+        })?;
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 2);
+        assert!(events[0].contains("authority=127.0.0.1:8080"));
+        assert!(events[1].contains("status_code=200"));
+        assert!(events[1].contains("slow_path=false"));
+        Ok(())
+    }
+
+    #[test]
+    fn receive_response_small_read_buf_test() -> Result<()> {
+        executor::block_on(async {
+            let sample_handshake = "HTTP/1.1 200 OK\r\n\
+                                    \r\n";
+            let sample_post_handshake_data = "this is already the proxied content";
+            let sample_res = sample_handshake.to_string() + sample_post_handshake_data;
+            let mut socket = Cursor::new(sample_res);
+
+            // Use small read buffer size to force non-happy-path.
+            const BUF_SIZE: usize = 4;
+            let mut read_buf = [0u8; BUF_SIZE];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+
+            // Prepare the estimates for the leftover data.
+            let extra_read = (BUF_SIZE - (sample_handshake.len() % BUF_SIZE)) % BUF_SIZE;
+            let expected_data = &sample_post_handshake_data[..extra_read];
+
+            assert_eq!(
+                outcome.data_after_handshake.as_slice(),
+                expected_data.as_bytes()
+            );
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.response_parts.reason_phrase, "OK");
+            assert_eq!(outcome.response_parts.headers.len(), 0);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_read_buf_too_small() {
+        executor::block_on(async {
+            let config = response::Config {
+                min_read_buf_len: 16,
+                ..Default::default()
+            };
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+            let mut read_buf = [0u8; 1];
+            let err = receive_response_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert_eq!(socket.position(), 0);
+        })
+    }
+
+    #[test]
+    fn receive_response_trickled_parse_calls() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = TrickleReader {
+                data: b"HTTP/1.1 200 OK\r\n\
+                        X-Custom: Sample Value\r\n\
+                        \r\n\
+                        this is already the proxied content",
+                chunk: 1,
+            };
+            let mut read_buf = [0u8; 1024];
+
+            let parser = CountingParser::default();
+            let outcome = receive_response_with_parser(&mut socket, &parser, &mut read_buf).await?;
+            assert!(parser.calls.get() <= 2);
+
+            assert!(outcome.data_after_handshake.is_empty());
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.response_parts.headers.len(), 1);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn contains_head_end_test() {
+        assert!(!contains_head_end(b""));
+        assert!(!contains_head_end(b"HTTP/1.1 200 OK\r\n"));
+        assert!(!contains_head_end(b"HTTP/1.1 200 OK\r\n\r"));
+        assert!(contains_head_end(b"HTTP/1.1 200 OK\r\n\r\n"));
+        assert!(contains_head_end(b"HTTP/1.1 200 OK\n\n"));
+        assert!(contains_head_end(b"\n\r\nbody"));
+    }
+
+    #[test]
+    fn parse_response_complete() -> Result<()> {
+        let head = "HTTP/1.1 200 Connection established\r\n\
+                    Proxy-Agent: test\r\n\
+                    \r\n";
+        let buf = format!("{}tunneled data", head);
+        let (parts, consumed) = parse_response(buf.as_bytes())?;
+        assert_eq!(consumed, head.len());
+        assert_eq!(parts.status_code, 200);
+        assert_eq!(parts.reason_phrase, "Connection established");
+        assert_eq!(parts.headers["proxy-agent"], "test");
+        Ok(())
+    }
+
+    #[test]
+    fn parse_request_headers_block() -> Result<()> {
+        let headers = parse_request_headers(
+            b"Proxy-Authorization: Basic aGVsbG86d29ybGQ=\r\n\
+              Via: 1.1 a\r\n\
+              Via: 1.1 b\r\n\
+              \r\n",
+        )?;
+        assert_eq!(headers.len(), 3);
+        assert_eq!(headers["proxy-authorization"], "Basic aGVsbG86d29ybGQ=");
+        let via: Vec<_> = headers.get_all("via").iter().collect();
+        assert_eq!(via, ["1.1 a", "1.1 b"]);
+
+        let err = parse_request_headers(b"Bad Name: value\r\n\r\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+        let err = parse_request_headers(b"Via: 1.1 a\r\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        Ok(())
+    }
+
+    #[test]
+    fn parse_response_partial() {
+        let err = parse_response(b"HTTP/1.1 200 OK\r\nProxy-Agent: te").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn parse_response_invalid() {
+        let err = parse_response(b"NOT HTTP\r\n\r\n").unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+    }
+
+    /// Accepts nothing but `200 Connection established`.
+    struct StrictParser;
+
+    impl ResponseParser for StrictParser {
+        fn parse(&self, buf: &[u8]) -> Result<Option<(ResponseParts, usize)>> {
+            let parsed = response::Config::default().parse(buf)?;
+            if let Some((parts, _)) = &parsed {
+                if parts.status_code != 200 || parts.reason_phrase != "Connection established" {
+                    return Err(Error::new(ErrorKind::InvalidData, "unexpected status"));
+                }
+            }
+            Ok(parsed)
+        }
+    }
+
+    #[test]
+    fn receive_response_with_custom_parser() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = Cursor::new("HTTP/1.1 200 Connection established\r\n\r\ndata");
+            let mut read_buf = [0u8; 1024];
+            let outcome =
+                receive_response_with_parser(&mut socket, &StrictParser, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"data");
+
+            let mut socket = Cursor::new("HTTP/1.1 204 No Content\r\n\r\n");
+            let err = receive_response_with_parser(&mut socket, &StrictParser, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_rejects_early_data() -> Result<()> {
+        executor::block_on(async {
+            let config = response::Config {
+                allow_early_data: false,
+                ..Default::default()
+            };
+            let mut read_buf = [0u8; 1024];
+
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\n\r\nearly");
+            let err = receive_response_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+            let outcome = receive_response_with_config(&mut socket, &config, &mut read_buf).await?;
+            assert!(!outcome.has_trailing_data());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn default_config_matches_free_functions() -> Result<()> {
+        executor::block_on(async {
+            let mut headers = HeaderMap::new();
+            headers.insert("X-First", HeaderValue::from_static("1"));
+
+            let mut expected_req = Cursor::new(vec![]);
+            send_request(&mut expected_req, "127.0.0.1", 8080, &headers).await?;
+
+            let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\nbody");
+            let mut socket = merge_io::MergeIO::new(reader, Cursor::new(vec![]));
+            let mut read_buf = [0u8; 1024];
+            let config = HandshakeConfig::default();
+            let outcome = handshake_with_config(
+                &mut socket,
+                &config,
+                "127.0.0.1",
+                8080,
+                &headers,
+                &mut read_buf,
+            )
+            .await?;
+
+            assert_eq!(socket.writer().get_ref(), expected_req.get_ref());
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"body");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_rejects_success_body() -> Result<()> {
+        executor::block_on(async {
+            let config = response::Config {
+                reject_success_body: true,
+                ..Default::default()
+            };
+            let mut read_buf = [0u8; 1024];
+
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\n");
+            let err = receive_response_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n");
+            let err = receive_response_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\nContent-Length: 0\r\n\r\n");
+            receive_response_with_config(&mut socket, &config, &mut read_buf).await?;
+
+            let mut socket = Cursor::new("HTTP/1.1 502 Bad Gateway\r\nContent-Length: 5\r\n\r\n");
+            receive_response_with_config(&mut socket, &config, &mut read_buf).await?;
+            Ok(())
+        })
+    }
+
+    fn response_with_headers(count: usize) -> String {
+        let mut response = String::from("HTTP/1.1 200 OK\r\n");
+        for i in 0..count {
+            response.push_str(&format!("X-Header-{}: {}\r\n", i, i));
+        }
+        response.push_str("\r\n");
+        response
+    }
+
+    #[test]
+    fn headers_saturation() -> Result<()> {
+        let (parts, _) = parse_response(response_with_headers(15).as_bytes())?;
+        assert!(!parts.headers_saturated());
+
+        let (parts, _) = parse_response(response_with_headers(16).as_bytes())?;
+        assert!(parts.headers_saturated());
+
+        let err = parse_response(response_with_headers(17).as_bytes()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(err.to_string().contains("more than 16 headers"));
+        Ok(())
+    }
+
+    #[test]
+    fn receive_response_slow_path_flag() -> Result<()> {
+        executor::block_on(async {
+            let data = "HTTP/1.1 200 Connection established\r\n\r\n";
+
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut Cursor::new(data), &mut read_buf).await?;
+            assert!(!outcome.slow_path);
+
+            let mut read_buf = [0u8; 16];
+            let outcome = receive_response(&mut Cursor::new(data), &mut read_buf).await?;
+            assert!(outcome.slow_path);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_split_terminator() -> Result<()> {
+        executor::block_on(async {
+            const HEAD_LEN: usize = 38;
+            let data: &'static [u8] = b"HTTP/1.1 200 OK\r\nProxy-Agent: test\r\n\r\nbody";
+            assert_eq!(&data[HEAD_LEN - 4..HEAD_LEN], b"\r\n\r\n");
+
+            // Split the terminator at every position, including right before
+            // it, with the body coming right after it in the same read.
+            for split in HEAD_LEN - 4..HEAD_LEN {
+                let mut socket = ChunksReader {
+                    chunks: vec![&data[..split], &data[split..], &b"more"[..]].into(),
+                };
+                let mut read_buf = [0u8; 1024];
+                let outcome = receive_response(&mut socket, &mut read_buf).await?;
+                assert_eq!(
+                    outcome.response_parts.status_code, 200,
+                    "split at {}",
+                    split
+                );
+                assert_eq!(outcome.response_parts.headers["proxy-agent"], "test");
+                assert_eq!(
+                    outcome.data_after_handshake.as_slice(),
+                    b"body",
+                    "split at {}",
+                    split
+                );
+            }
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_force_carry_on() -> Result<()> {
+        executor::block_on(async {
+            let data = "HTTP/1.1 200 OK\r\nProxy-Agent: test\r\n\r\ndata";
+            let mut read_buf = [0u8; 1024];
+
+            let config = response::Config::default();
+            let mut socket = Cursor::new(data);
+            let mut reader = SliceReader::new(&mut socket, &mut read_buf);
+            let (happy, head) =
+                receive_with_head(&mut reader, &config, Reading::new(&config), &[]).await?;
+            assert!(matches!(head, Head::ReadBuf(_)));
+
+            let config = response::Config {
+                force_carry_on: true,
+                ..Default::default()
+            };
+            let mut socket = Cursor::new(data);
+            let mut reader = SliceReader::new(&mut socket, &mut read_buf);
+            let (forced, head) =
+                receive_with_head(&mut reader, &config, Reading::new(&config), &[]).await?;
+            assert!(matches!(head, Head::CarryOn(_)));
+
+            assert_eq!(
+                forced.response_parts.status_code,
+                happy.response_parts.status_code
+            );
+            assert_eq!(
+                forced.response_parts.reason_phrase,
+                happy.response_parts.reason_phrase
+            );
+            assert_eq!(forced.response_parts.headers, happy.response_parts.headers);
+            assert_eq!(forced.data_after_handshake, happy.data_after_handshake);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_max_headers_trickled() -> Result<()> {
+        executor::block_on(async {
+            let data = Box::leak(response_with_headers(response::MAX_HEADERS).into_boxed_str());
+            let mut socket = TrickleReader {
+                data: data.as_bytes(),
+                chunk: 7,
+            };
+            let mut read_buf = [0u8; 16];
+
+            // The header scratch is set up on every parse, but the slow path
+            // only parses the first read and the one completing the head.
+            let parser = CountingParser::default();
+            let outcome = receive_response_with_parser(&mut socket, &parser, &mut read_buf).await?;
+            assert_eq!(parser.calls.get(), 2);
+
+            let headers = &outcome.response_parts.headers;
+            assert_eq!(headers.len(), response::MAX_HEADERS);
+            for i in 0..response::MAX_HEADERS {
+                assert_eq!(headers[format!("x-header-{}", i).as_str()], i.to_string());
+            }
+            assert!(outcome.data_after_handshake.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn handshake_rejects_port_zero() {
+        executor::block_on(async {
+            let mut socket = Cursor::new(vec![]);
+            let mut read_buf = [0u8; 1024];
+            let err = handshake(
+                &mut socket,
+                "127.0.0.1",
+                0,
+                &HeaderMap::new(),
+                &mut read_buf,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+            assert!(socket.get_ref().is_empty());
+        })
+    }
+
+    #[test]
+    fn receive_response_visits_headers_in_order() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = TrickleReader {
+                data: b"HTTP/1.1 200 OK\r\n\
+                        Via: 1.1 first\r\n\
+                        Proxy-Agent: test\r\n\
+                        Via: 1.1 second\r\n\
+                        \r\n",
+                chunk: 16,
+            };
+            let mut read_buf = [0u8; 16];
+            let mut visited = vec![];
+            let outcome =
+                receive_response_with_visitor(&mut socket, &mut read_buf, |name, value| {
+                    visited.push((name.to_string(), value.to_vec()))
+                })
+                .await?;
+
+            assert_eq!(outcome.response_parts.headers.len(), 3);
+            assert_eq!(
+                visited,
+                vec![
+                    ("Via".to_string(), b"1.1 first".to_vec()),
+                    ("Proxy-Agent".to_string(), b"test".to_vec()),
+                    ("Via".to_string(), b"1.1 second".to_vec()),
+                ]
+            );
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_skips_interim() -> Result<()> {
+        executor::block_on(async {
+            let mut read_buf = [0u8; 1024];
 
-pub async fn receive_response<'buf, AR>(
-    stream: &mut AR,
-    read_buf: &mut [u8],
-) -> Result<HandshakeOutcome>
-where
-    AR: AsyncRead + Unpin,
-{
-    // Happy path - we expect the response to be reasonably small and to come in
-    // complete as a single buffer via a single read.
-    // In this case we don't need to allocate and carry-on second buffer.
+            // Both in a single read.
+            let mut socket =
+                Cursor::new("HTTP/1.1 100 Continue\r\n\r\nHTTP/1.1 200 OK\r\n\r\ndata");
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"data");
+
+            // The final response in the subsequent reads.
+            let mut socket = ChunksReader {
+                chunks: vec![
+                    &b"HTTP/1.1 100 Continue\r\n\r\n"[..],
+                    b"HTTP/1.1 102 Processing\r\n\r\nHTTP/1.1 200",
+                    b" OK\r\n\r\n",
+                ]
+                .into(),
+            };
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert!(!outcome.has_trailing_data());
+            Ok(())
+        })
+    }
 
-    let first_buf = {
-        let total = stream.read(read_buf).await?;
-        let buf = &read_buf[..total];
+    #[test]
+    fn receive_response_keeps_switching_protocols() -> Result<()> {
+        executor::block_on(async {
+            let mut read_buf = [0u8; 1024];
+            let mut socket = Cursor::new("HTTP/1.1 101 Switching Protocols\r\n\r\ndata");
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 101);
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"data");
+            Ok(())
+        })
+    }
 
-        let mut response_headers = [httparse::EMPTY_HEADER; 16];
-        let mut response = httparse::Response::new(&mut response_headers);
+    #[test]
+    fn receive_response_bare_lf() -> Result<()> {
+        executor::block_on(async {
+            let config = response::Config {
+                allow_bare_lf: true,
+                ..Default::default()
+            };
+            let mut read_buf = [0u8; 1024];
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\nProxy-Agent: test\n\nbody");
+            let outcome = receive_response_with_config(&mut socket, &config, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.response_parts.headers["proxy-agent"], "test");
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"body");
 
-        let status = response
-            .parse(buf)
-            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+            // And so does the head end check on the slow path.
+            let mut socket = TrickleReader {
+                data: b"HTTP/1.1 200 OK\nProxy-Agent: test\n\nbody",
+                chunk: 3,
+            };
+            let mut read_buf = [0u8; 16];
+            let outcome = receive_response_with_config(&mut socket, &config, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
 
-        match status {
-            httparse::Status::Partial => buf,
-            httparse::Status::Complete(consumed) => {
-                return Ok(HandshakeOutcome::new(response, Vec::from(&buf[consumed..])))
+            // They're rejected by default, even if just one line has it.
+            for data in &[
+                "HTTP/1.1 200 OK\n\nbody",
+                "HTTP/1.1 200 OK\r\nProxy-Agent: test\n\r\n",
+                "HTTP/1.1 200 OK\r\n\n",
+            ] {
+                let mut socket = Cursor::new(*data);
+                let err = receive_response(&mut socket, &mut read_buf)
+                    .await
+                    .unwrap_err();
+                assert_eq!(err.kind(), ErrorKind::InvalidData, "{:?}", data);
             }
+
+            // The data after the head isn't checked.
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\n\r\nbody\n");
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"body\n");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn send_request_flushes() -> Result<()> {
+        /// A writer that tracks whether it was flushed after the last write.
+        #[derive(Default)]
+        struct FlushTracking {
+            written: Vec<u8>,
+            flushed: bool,
         }
-    };
 
-    // We didn't exit early on error or completion, this means we're at slower
-    // path and we need a carry-on buffer.
+        impl AsyncWrite for FlushTracking {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<Result<usize>> {
+                self.written.extend_from_slice(buf);
+                self.flushed = false;
+                Poll::Ready(Ok(buf.len()))
+            }
 
-    // TODO: allow user to customize the data structure used for a carry-on
-    // buffer. This is useful in case user wants to limit the amount of memory
-    // this buffer can grow to, or for the cases when a more optimized data
-    // structure is at hand.
-    let mut carry_on_buf = Vec::from(first_buf);
-    loop {
-        let total = stream.read(read_buf).await?;
-        let buf = &read_buf[..total];
-        carry_on_buf.extend_from_slice(buf);
+            fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+                self.flushed = true;
+                Poll::Ready(Ok(()))
+            }
 
-        let mut response_headers = [httparse::EMPTY_HEADER; 16];
-        let mut response = httparse::Response::new(&mut response_headers);
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
 
-        let status = response
-            .parse(carry_on_buf.as_slice())
-            .map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
-        match status {
-            httparse::Status::Partial => continue,
-            httparse::Status::Complete(consumed) => {
-                return Ok(HandshakeOutcome::new(
-                    response,
-                    Vec::from(&carry_on_buf[consumed..]),
-                ))
+        executor::block_on(async {
+            let mut writer = FlushTracking::default();
+            send_request(&mut writer, "127.0.0.1", 8080, &HeaderMap::new()).await?;
+            assert!(!writer.written.is_empty());
+            assert!(writer.flushed);
+
+            let mut writer = FlushTracking::default();
+            send_request_ordered(&mut writer, "127.0.0.1", 8080, &[]).await?;
+            assert!(writer.flushed);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn handshake_over_buffered_writer() -> Result<()> {
+        /// A proxy behind a buffered writer: it only sees the request once
+        /// it's flushed, and only responds after that. Reading before then
+        /// would block forever, so it's reported as an error instead.
+        #[derive(Default)]
+        struct BufferedProxy {
+            buffered: Vec<u8>,
+            flushed: Vec<u8>,
+            response: &'static [u8],
+        }
+
+        impl AsyncRead for BufferedProxy {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<Result<usize>> {
+                if !contains_head_end(&self.flushed) {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::WouldBlock,
+                        "waiting for the response to the unflushed request",
+                    )));
+                }
+                let n = buf.len().min(self.response.len());
+                buf[..n].copy_from_slice(&self.response[..n]);
+                self.response = &self.response[n..];
+                Poll::Ready(Ok(n))
             }
-        };
+        }
+
+        impl AsyncWrite for BufferedProxy {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<Result<usize>> {
+                self.buffered.extend_from_slice(buf);
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+                let this = &mut *self;
+                this.flushed.append(&mut this.buffered);
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        executor::block_on(async {
+            let mut stream = BufferedProxy {
+                response: b"HTTP/1.1 200 OK\r\n\r\n",
+                ..Default::default()
+            };
+            let mut read_buf = [0u8; 1024];
+            let outcome = handshake(
+                &mut stream,
+                "127.0.0.1",
+                8080,
+                &HeaderMap::new(),
+                &mut read_buf,
+            )
+            .await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert!(stream.buffered.is_empty());
+            Ok(())
+        })
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use crate::http::HeaderValue;
-    use futures::{executor, io::Cursor};
+    #[test]
+    fn receive_response_into_reused_parts() -> Result<()> {
+        executor::block_on(async {
+            let mut response_parts = ResponseParts {
+                status_code: 0,
+                reason_phrase: String::new(),
+                headers: HeaderMap::new(),
+            };
+            let mut read_buf = [0u8; 1024];
+
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\nX-First: 1\r\n\r\ndata");
+            let data =
+                receive_response_into(&mut socket, &mut response_parts, &mut read_buf).await?;
+            assert_eq!(response_parts.status_code, 200);
+            assert_eq!(response_parts.headers["x-first"], "1");
+            assert_eq!(data.as_slice(), b"data");
+            let capacity = response_parts.headers.capacity();
+
+            let mut socket = Cursor::new("HTTP/1.1 407 Auth Required\r\nX-Second: 2\r\n\r\n");
+            let data =
+                receive_response_into(&mut socket, &mut response_parts, &mut read_buf).await?;
+            assert_eq!(response_parts.status_code, 407);
+            assert_eq!(response_parts.reason_phrase, "Auth Required");
+            assert_eq!(response_parts.headers.len(), 1);
+            assert_eq!(response_parts.headers["x-second"], "2");
+            assert_eq!(response_parts.headers.capacity(), capacity);
+            assert!(data.is_empty());
+            Ok(())
+        })
+    }
 
     #[test]
-    fn send_request_without_headers() -> Result<()> {
+    fn receive_response_caps_reason_len() -> Result<()> {
         executor::block_on(async {
-            let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
-                              Host: 127.0.0.1:8080\r\n\
-                              \r\n";
-            let mut socket = Cursor::new(vec![0u8; 1024]);
-            let headers = HeaderMap::new();
-            send_request(&mut socket, "127.0.0.1", 8080, &headers).await?;
+            let config = response::Config {
+                max_reason_len: 8,
+                ..Default::default()
+            };
+            let mut read_buf = [0u8; 1024];
 
-            assert_eq!(
-                &socket.get_ref()[..socket.position() as usize],
-                sample_res.as_bytes(),
-            );
+            let mut socket = Cursor::new("HTTP/1.1 200 Connection established\r\n\r\n");
+            let err = receive_response_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+            let mut socket = Cursor::new("HTTP/1.1 200 Accepted\r\n\r\n");
+            let outcome = receive_response_with_config(&mut socket, &config, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.reason_phrase, "Accepted");
+
+            // The default is generous, but still there.
+            let reason = "x".repeat(response::DEFAULT_MAX_REASON_LEN + 1);
+            let mut socket = Cursor::new(format!("HTTP/1.1 200 {}\r\n\r\n", reason));
+            let mut read_buf = [0u8; 2048];
+            let err = receive_response(&mut socket, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
             Ok(())
         })
     }
 
     #[test]
-    fn send_request_with_headers() -> Result<()> {
+    fn receive_response_rejects_obs_fold() -> Result<()> {
         executor::block_on(async {
-            let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
-                              Host: 127.0.0.1:8080\r\n\
-                              proxy-authorization: Basic aGVsbG86d29ybGQ=\r\n\
-                              \r\n";
-            let mut socket = Cursor::new(vec![0u8; 1024]);
-            let mut headers = HeaderMap::new();
-            headers.insert(
-                "Proxy-Authorization",
-                HeaderValue::from_static("Basic aGVsbG86d29ybGQ="),
-            );
-            send_request(&mut socket, "127.0.0.1", 8080, &headers).await?;
+            let data = "HTTP/1.1 200 OK\r\nProxy-Agent: a\r\n\tfolded\r\n\r\n";
+            let mut read_buf = [0u8; 1024];
+
+            // `httparse` rejects it anyway, just not as clearly.
+            let mut socket = Cursor::new(data);
+            let err = receive_response(&mut socket, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
 
+            let config = response::Config {
+                reject_obs_fold: true,
+                ..Default::default()
+            };
+            let mut socket = Cursor::new(data);
+            let err = receive_response_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
             assert_eq!(
-                &socket.get_ref()[..socket.position() as usize],
-                sample_res.as_bytes(),
+                err.to_string(),
+                "response header uses obsolete line folding"
             );
+
+            // The leading whitespace past the head is none of its business.
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\nProxy-Agent: a\r\n\r\n data");
+            let outcome = receive_response_with_config(&mut socket, &config, &mut read_buf).await?;
+            assert_eq!(outcome.data_after_handshake.as_slice(), b" data");
             Ok(())
         })
     }
 
     #[test]
-    fn receive_response_test() -> Result<()> {
+    fn receive_response_raw_head() -> Result<()> {
         executor::block_on(async {
-            let sample_res = "HTTP/1.1 200 OK\r\n\
-                              \r\n\
-                              this is already the proxied content";
-            let mut socket = Cursor::new(sample_res);
+            let head = "HTTP/1.1 200 OK\r\nProxy-Agent: test\r\n\r\n";
+
+            // Borrowed in the happy path.
+            let mut socket = Cursor::new(format!("{}data", head));
             let mut read_buf = [0u8; 1024];
-            let outcome = receive_response(&mut socket, &mut read_buf).await?;
-            assert_eq!(
-                outcome.data_after_handshake.as_slice(),
-                "this is already the proxied content".as_bytes()
-            );
-            assert_eq!(outcome.response_parts.status_code, 200);
-            assert_eq!(outcome.response_parts.reason_phrase, "OK");
-            assert_eq!(outcome.response_parts.headers.len(), 0);
+            let (outcome, raw) = receive_response_raw(&mut socket, &mut read_buf).await?;
+            assert!(matches!(raw, Cow::Borrowed(_)));
+            assert_eq!(&*raw, head.as_bytes());
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"data");
+
+            // Owned when it took more reads.
+            let mut socket = TrickleReader {
+                data: b"HTTP/1.1 200 OK\r\nProxy-Agent: test\r\n\r\ndata",
+                chunk: 16,
+            };
+            let mut read_buf = [0u8; 16];
+            let (_, raw) = receive_response_raw(&mut socket, &mut read_buf).await?;
+            assert!(matches!(raw, Cow::Owned(_)));
+            assert_eq!(&*raw, head.as_bytes());
+
+            // The final one after the interim response.
+            let mut socket = Cursor::new(format!("HTTP/1.1 100 Continue\r\n\r\n{}", head));
+            let mut read_buf = [0u8; 1024];
+            let (_, raw) = receive_response_raw(&mut socket, &mut read_buf).await?;
+            assert_eq!(&*raw, head.as_bytes());
             Ok(())
         })
     }
 
     #[test]
-    fn receive_response_with_headers() -> Result<()> {
+    fn handshake_parks_on_pending() {
+        use futures::task::{waker, ArcWake};
+        use std::future::Future;
+        use std::rc::Rc;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use std::task::Waker;
+
+        #[derive(Default)]
+        struct State {
+            data: Option<&'static [u8]>,
+            read_polls: usize,
+            waker: Option<Waker>,
+        }
+
+        /// A stream that is pending until the data is supplied.
+        struct ParkingStream(Rc<RefCell<State>>);
+
+        impl AsyncRead for ParkingStream {
+            fn poll_read(
+                self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<Result<usize>> {
+                let mut state = self.0.borrow_mut();
+                state.read_polls += 1;
+                match state.data.take() {
+                    Some(data) => {
+                        buf[..data.len()].copy_from_slice(data);
+                        Poll::Ready(Ok(data.len()))
+                    }
+                    None => {
+                        state.waker = Some(cx.waker().clone());
+                        Poll::Pending
+                    }
+                }
+            }
+        }
+
+        impl AsyncWrite for ParkingStream {
+            fn poll_write(
+                self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<Result<usize>> {
+                Poll::Ready(Ok(buf.len()))
+            }
+
+            fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+
+            fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        #[derive(Default)]
+        struct CountingWaker(AtomicUsize);
+
+        impl ArcWake for CountingWaker {
+            fn wake_by_ref(arc_self: &Arc<Self>) {
+                arc_self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let state = Rc::new(RefCell::new(State::default()));
+        let wakes = Arc::new(CountingWaker::default());
+        let waker = waker(wakes.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut stream = ParkingStream(state.clone());
+        let mut read_buf = [0u8; 1024];
+        let headers = HeaderMap::new();
+        let mut handshake = Box::pin(handshake(
+            &mut stream,
+            "127.0.0.1",
+            8080,
+            &headers,
+            &mut read_buf,
+        ));
+
+        // Parks after a single read attempt, without waking itself up.
+        assert!(handshake.as_mut().poll(&mut cx).is_pending());
+        assert_eq!(state.borrow().read_polls, 1);
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 0);
+        assert!(state.borrow().waker.is_some());
+
+        // Resumes once the data arrives.
+        state.borrow_mut().data = Some(b"HTTP/1.1 200 OK\r\n\r\n");
+        state.borrow_mut().waker.take().unwrap().wake();
+        assert_eq!(wakes.0.load(Ordering::SeqCst), 1);
+        match handshake.as_mut().poll(&mut cx) {
+            Poll::Ready(Ok(outcome)) => assert_eq!(outcome.response_parts.status_code, 200),
+            other => panic!("expected the handshake to complete, got {:?}", other),
+        }
+        assert_eq!(state.borrow().read_polls, 2);
+    }
+
+    #[test]
+    fn receive_response_repeated_headers() -> Result<()> {
         executor::block_on(async {
-            let sample_res = "HTTP/1.1 200 OK\r\n\
-                              X-Custom: Sample Value\r\n\
-                              \r\n\
-                              this is already the proxied content";
+            let sample_res = "HTTP/1.1 407 Proxy Authentication Required\r\n\
+                              Proxy-Authenticate: Negotiate\r\n\
+                              Proxy-Authenticate: Basic realm=\"proxy\"\r\n\
+                              \r\n";
             let mut socket = Cursor::new(sample_res);
             let mut read_buf = [0u8; 1024];
             let outcome = receive_response(&mut socket, &mut read_buf).await?;
-            assert_eq!(
-                outcome.data_after_handshake.as_slice(),
-                "this is already the proxied content".as_bytes()
+            let values: Vec<_> = outcome
+                .response_parts
+                .headers
+                .get_all("proxy-authenticate")
+                .iter()
+                .collect();
+            assert_eq!(values, ["Negotiate", "Basic realm=\"proxy\""]);
+
+            let challenges = outcome.response_parts.proxy_authenticate();
+            assert_eq!(challenges.len(), 2);
+            assert!(challenges[1].is_scheme("basic"));
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_caps_status_line_while_reading() {
+        /// Sends the status line with an endless reason phrase.
+        struct EndlessReason {
+            sent: usize,
+        }
+
+        impl AsyncRead for EndlessReason {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<Result<usize>> {
+                const PREFIX: &[u8] = b"HTTP/1.1 200 ";
+                let n = buf.len().min(16);
+                for (i, byte) in buf[..n].iter_mut().enumerate() {
+                    *byte = PREFIX.get(self.sent + i).copied().unwrap_or(b'x');
+                }
+                self.sent += n;
+                Poll::Ready(Ok(n))
+            }
+        }
+
+        executor::block_on(async {
+            let config = response::Config {
+                max_reason_len: 64,
+                ..Default::default()
+            };
+            let mut socket = EndlessReason { sent: 0 };
+            let mut read_buf = [0u8; 1024];
+            let err = receive_response_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            // Gave up one read past the limit.
+            assert!(socket.sent <= 64 + 15 + 16, "read {} bytes", socket.sent);
+        })
+    }
+
+    #[test]
+    fn receive_response_into_after_interim() -> Result<()> {
+        executor::block_on(async {
+            let mut headers = HeaderMap::with_capacity(32);
+            headers.insert("x-old", HeaderValue::from_static("1"));
+            let mut response_parts = ResponseParts {
+                status_code: 200,
+                reason_phrase: "OK".to_string(),
+                headers,
+            };
+            let capacity = response_parts.headers.capacity();
+            let mut read_buf = [0u8; 1024];
+
+            // The final response gets the allocation, not the interim one.
+            let mut socket = Cursor::new(
+                "HTTP/1.1 100 Continue\r\nX-Interim: 1\r\n\r\n\
+                 HTTP/1.1 200 OK\r\nX-Final: 1\r\n\r\n",
             );
+            receive_response_into(&mut socket, &mut response_parts, &mut read_buf).await?;
+            assert_eq!(response_parts.headers.len(), 1);
+            assert_eq!(response_parts.headers["x-final"], "1");
+            assert_eq!(response_parts.headers.capacity(), capacity);
+
+            // The headers are handed back on error.
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\nX-Trunc");
+            let err = receive_response_into(&mut socket, &mut response_parts, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+            assert_eq!(response_parts.headers["x-final"], "1");
+            assert_eq!(response_parts.headers.capacity(), capacity);
+
+            // Cleared, if an interim response came in before the error.
+            let mut socket = Cursor::new("HTTP/1.1 100 Continue\r\n\r\nNOT HTTP\r\n\r\n");
+            let err = receive_response_into(&mut socket, &mut response_parts, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert!(response_parts.headers.is_empty());
+            assert_eq!(response_parts.headers.capacity(), capacity);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_vec_after_interim() -> Result<()> {
+        executor::block_on(async {
+            let sample_res = "HTTP/1.1 100 Continue\r\n\r\n\
+                              HTTP/1.1 200 Connection established\r\n\
+                              Proxy-Agent: test\r\n\
+                              \r\n\
+                              data";
+            let mut socket = Cursor::new(sample_res.as_bytes());
+            let mut read_buf = Vec::new();
+            let outcome = receive_response_vec(&mut socket, &mut read_buf).await?;
             assert_eq!(outcome.response_parts.status_code, 200);
-            assert_eq!(outcome.response_parts.reason_phrase, "OK");
-            assert_eq!(outcome.response_parts.headers.len(), 1);
-            assert_eq!(
-                outcome.response_parts.headers.get("x-custom").unwrap(),
-                &"Sample Value"
-            );
+            assert_eq!(outcome.response_parts.headers["proxy-agent"], "test");
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"data");
+            assert_eq!(read_buf.as_slice(), sample_res.as_bytes());
+
+            // Also with the final response coming in over many reads.
+            let mut socket = TrickleReader {
+                data: sample_res.as_bytes(),
+                chunk: 5,
+            };
+            let mut read_buf = Vec::new();
+            let outcome = receive_response_vec(&mut socket, &mut read_buf).await?;
+            assert!(outcome.slow_path);
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert!(read_buf.ends_with(&outcome.data_after_handshake));
             Ok(())
         })
     }
 
     #[test]
-    fn receive_response_small_read_buf_test() -> Result<()> {
+    fn receive_response_vec_with_config_checks() {
         executor::block_on(async {
-            let sample_handshake = "HTTP/1.1 200 OK\r\n\
-                                    \r\n";
-            let sample_post_handshake_data = "this is already the proxied content";
-            let sample_res = sample_handshake.to_string() + sample_post_handshake_data;
-            let mut socket = Cursor::new(sample_res);
+            let config = response::Config {
+                max_reason_len: 8,
+                ..Default::default()
+            };
+            let mut socket = TrickleReader {
+                data: b"HTTP/1.1 200 Connection established, and a lot more",
+                chunk: 4,
+            };
+            let mut read_buf = Vec::new();
+            let err = receive_response_vec_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
 
-            // Use small read buffer size to force non-happy-path.
-            const BUF_SIZE: usize = 4;
-            let mut read_buf = [0u8; BUF_SIZE];
-            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            let config = response::Config {
+                allow_early_data: false,
+                ..Default::default()
+            };
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\n\r\nearly");
+            let err = receive_response_vec_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        })
+    }
 
-            // Prepare the estimates for the leftover data.
-            let extra_read = (BUF_SIZE - (sample_handshake.len() % BUF_SIZE)) % BUF_SIZE;
-            let expected_data = &sample_post_handshake_data[..extra_read];
+    #[test]
+    fn receive_response_vec_keeps_min_read_buf_len() -> Result<()> {
+        /// Panics on the reads into buffers smaller than `min`.
+        struct MinReader {
+            data: &'static [u8],
+            min: usize,
+        }
 
-            assert_eq!(
-                outcome.data_after_handshake.as_slice(),
-                expected_data.as_bytes()
-            );
+        impl AsyncRead for MinReader {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<Result<usize>> {
+                assert!(buf.len() >= self.min, "read into {} bytes", buf.len());
+                let n = buf.len().min(self.data.len()).min(self.min);
+                buf[..n].copy_from_slice(&self.data[..n]);
+                self.data = &self.data[n..];
+                Poll::Ready(Ok(n))
+            }
+        }
+
+        executor::block_on(async {
+            let mut config = response::Config {
+                min_read_buf_len: 600,
+                ..Default::default()
+            };
+            let mut socket = MinReader {
+                data: b"HTTP/1.1 200 OK\r\n\r\n",
+                min: 600,
+            };
+            // Room for the first read only.
+            let mut read_buf = Vec::with_capacity(600);
+            let outcome =
+                receive_response_vec_with_config(&mut socket, &config, &mut read_buf).await?;
             assert_eq!(outcome.response_parts.status_code, 200);
-            assert_eq!(outcome.response_parts.reason_phrase, "OK");
-            assert_eq!(outcome.response_parts.headers.len(), 0);
+
+            let mut socket = MinReader {
+                data: b"HTTP/1.1 200 OK\r\n\r\n",
+                min: 8,
+            };
+            // The second read leaves less than the minimum spare.
+            let mut read_buf = Vec::with_capacity(20);
+            config.min_read_buf_len = 8;
+            let outcome =
+                receive_response_vec_with_config(&mut socket, &config, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_response_trims_header_values() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = Cursor::new("HTTP/1.1 200 OK\r\nX-Custom: \t trimmed \t\r\n\r\n");
+            let mut read_buf = [0u8; 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.headers["x-custom"], "trimmed");
             Ok(())
         })
     }
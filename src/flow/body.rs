@@ -0,0 +1,208 @@
+use crate::http::HeaderMap;
+use ::http::header::{CONTENT_LENGTH, TRANSFER_ENCODING};
+use futures_io::AsyncRead;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+
+/// Reads the response body framed according to the `headers`, decoding the
+/// chunked transfer coding if needed. Reading starts with the `leftover` data
+/// that came after the response head, and stops once `limit` bytes of the
+/// body are collected, truncating the rest.
+pub async fn read_body<AR>(
+    stream: &mut AR,
+    headers: &HeaderMap,
+    leftover: &[u8],
+    limit: usize,
+) -> Result<Vec<u8>>
+where
+    AR: AsyncRead + Unpin,
+{
+    let mut reader = BodyReader {
+        stream,
+        buf: Vec::from(leftover),
+        pos: 0,
+    };
+    if is_chunked(headers) {
+        return reader.read_chunked(limit).await;
+    }
+    match content_length(headers)? {
+        Some(len) => reader.read_len(len.min(limit)).await,
+        None => reader.read_to_eof(limit).await,
+    }
+}
+
+fn is_chunked(headers: &HeaderMap) -> bool {
+    // Chunked, if present, must be the last coding applied.
+    headers
+        .get_all(TRANSFER_ENCODING)
+        .iter()
+        .next_back()
+        .and_then(|value| value.as_bytes().rsplit(|&b| b == b',').next())
+        .is_some_and(|coding| coding.trim_ascii().eq_ignore_ascii_case(b"chunked"))
+}
+
+fn content_length(headers: &HeaderMap) -> Result<Option<usize>> {
+    let value = match headers.get(CONTENT_LENGTH) {
+        Some(value) => value,
+        None => return Ok(None),
+    };
+    value
+        .to_str()
+        .ok()
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(|len| Some(usize::try_from(len).unwrap_or(usize::MAX)))
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "invalid content-length"))
+}
+
+struct BodyReader<'a, AR> {
+    stream: &'a mut AR,
+    buf: Vec<u8>,
+    pos: usize,
+}
+
+impl<AR> BodyReader<'_, AR>
+where
+    AR: AsyncRead + Unpin,
+{
+    fn available(&self) -> &[u8] {
+        &self.buf[self.pos..]
+    }
+
+    /// Reads more data into the buffer, returning the amount read.
+    async fn fill(&mut self) -> Result<usize> {
+        let mut chunk = [0u8; 1024];
+        let total = super::read_retrying(self.stream, &mut chunk).await?;
+        self.buf.extend_from_slice(&chunk[..total]);
+        Ok(total)
+    }
+
+    async fn fill_to(&mut self, len: usize) -> Result<()> {
+        while self.available().len() < len {
+            if self.fill().await? == 0 {
+                return Err(Error::new(
+                    ErrorKind::UnexpectedEof,
+                    "stream ended before the response body was complete",
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    async fn read_len(&mut self, len: usize) -> Result<Vec<u8>> {
+        self.fill_to(len).await?;
+        Ok(Vec::from(&self.available()[..len]))
+    }
+
+    async fn read_to_eof(&mut self, limit: usize) -> Result<Vec<u8>> {
+        while self.available().len() < limit && self.fill().await? != 0 {}
+        let len = self.available().len().min(limit);
+        Ok(Vec::from(&self.available()[..len]))
+    }
+
+    async fn read_chunked(&mut self, limit: usize) -> Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let size = loop {
+                match httparse::parse_chunk_size(self.available())
+                    .map_err(|_| Error::new(ErrorKind::InvalidData, "invalid chunk size"))?
+                {
+                    httparse::Status::Complete((consumed, size)) => {
+                        self.pos += consumed;
+                        break usize::try_from(size).unwrap_or(usize::MAX);
+                    }
+                    httparse::Status::Partial => self.fill_to(self.available().len() + 1).await?,
+                }
+            };
+            if size == 0 {
+                // We don't care about the trailer section, if any.
+                return Ok(body);
+            }
+
+            let remaining = limit - body.len();
+            if size >= remaining {
+                self.fill_to(remaining).await?;
+                body.extend_from_slice(&self.available()[..remaining]);
+                return Ok(body);
+            }
+
+            self.fill_to(size + 2).await?;
+            let (data, crlf) = self.available()[..size + 2].split_at(size);
+            if crlf != b"\r\n" {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    "invalid chunk terminator",
+                ));
+            }
+            body.extend_from_slice(data);
+            self.pos += size + 2;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HeaderValue;
+    use futures::{executor, io::Cursor};
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn read_chunked_body() -> Result<()> {
+        executor::block_on(async {
+            let headers = headers("transfer-encoding", "chunked");
+            let mut socket = Cursor::new("llo\r\n6\r\n world\r\n0\r\n\r\n");
+            let body = read_body(&mut socket, &headers, b"5\r\nHe", 1024).await?;
+            assert_eq!(body.as_slice(), b"Hello world");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn read_chunked_body_truncated() -> Result<()> {
+        executor::block_on(async {
+            let headers = headers("transfer-encoding", "chunked");
+            let mut socket = Cursor::new("5\r\nHello\r\n6\r\n world\r\n0\r\n\r\n");
+            let body = read_body(&mut socket, &headers, b"", 7).await?;
+            assert_eq!(body.as_slice(), b"Hello w");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn read_content_length_body() -> Result<()> {
+        executor::block_on(async {
+            let headers = headers("content-length", "5");
+            let mut socket = Cursor::new("lo and more");
+            let body = read_body(&mut socket, &headers, b"Hel", 1024).await?;
+            assert_eq!(body.as_slice(), b"Hello");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn read_body_to_eof() -> Result<()> {
+        executor::block_on(async {
+            let mut socket = Cursor::new("lo");
+            let body = read_body(&mut socket, &HeaderMap::new(), b"Hel", 1024).await?;
+            assert_eq!(body.as_slice(), b"Hello");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn read_chunked_body_unexpected_eof() {
+        executor::block_on(async {
+            let headers = headers("transfer-encoding", "chunked");
+            let mut socket = Cursor::new("5\r\nHel");
+            let err = read_body(&mut socket, &headers, b"", 1024)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        })
+    }
+}
@@ -1,22 +1,107 @@
+use super::response;
+use crate::auth::{self, Challenge};
 use crate::http::{HeaderMap, HeaderName, HeaderValue};
+use crate::prepend_io_stream::PrependIoStream;
+use ::http::header::{AGE, CONNECTION, UPGRADE};
+use futures_io::{AsyncRead, AsyncWrite};
 use httparse::Response;
+use std::time::Duration;
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct ResponseParts {
     pub status_code: u16,
     pub reason_phrase: String,
     pub headers: HeaderMap,
 }
 
-/// Panics if response is not complete.
-fn parts_from_complete_response<'headers, 'buf: 'headers>(
+impl ResponseParts {
+    /// Parses the authentication challenges offered by the proxy.
+    pub fn proxy_authenticate(&self) -> Vec<Challenge> {
+        auth::challenges(&self.headers)
+    }
+
+    /// Whether the response used up all of the [`response::MAX_HEADERS`], so
+    /// that a proxy sending just one more would've been rejected.
+    pub fn headers_saturated(&self) -> bool {
+        self.headers.len() >= response::MAX_HEADERS
+    }
+
+    /// Whether the proxy established the tunnel, i.e. responded with 2xx.
+    pub fn is_tunnel_established(&self) -> bool {
+        (200..300).contains(&self.status_code)
+    }
+
+    /// Like [`is_tunnel_established`](Self::is_tunnel_established), but also
+    /// requires the conventional `Connection established` reason phrase.
+    pub fn is_tunnel_established_strict(&self) -> bool {
+        self.is_tunnel_established()
+            && self
+                .reason_phrase
+                .eq_ignore_ascii_case("Connection established")
+    }
+
+    /// The protocols listed in the `Upgrade` header, if it's there and is
+    /// valid text.
+    pub fn upgrade(&self) -> Option<&str> {
+        self.headers.get(UPGRADE)?.to_str().ok()
+    }
+
+    /// Whether any `Connection` header lists the `upgrade` option.
+    pub fn connection_upgrade(&self) -> bool {
+        self.headers
+            .get_all(CONNECTION)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|option| option.trim().eq_ignore_ascii_case("upgrade"))
+    }
+
+    /// The protocols the proxy offered to upgrade to, e.g. `h2c`, with both
+    /// the `Upgrade` and `Connection: upgrade` headers. This is just header
+    /// inspection, to let the caller fall back to some other protocol.
+    pub fn suggests_upgrade(&self) -> Option<&str> {
+        self.upgrade().filter(|_| self.connection_upgrade())
+    }
+
+    /// The `Date` header, if it's a valid HTTP date.
+    #[cfg(feature = "httpdate")]
+    pub fn date(&self) -> Option<std::time::SystemTime> {
+        let value = self.headers.get(::http::header::DATE)?.to_str().ok()?;
+        httpdate::parse_http_date(value).ok()
+    }
+
+    /// The headers as owned text, with invalid UTF-8 in the values replaced,
+    /// e.g. for logging or serialization.
+    pub fn headers_as_strings(&self) -> Vec<(String, String)> {
+        self.headers
+            .iter()
+            .map(|(name, value)| {
+                let value = String::from_utf8_lossy(value.as_bytes()).into_owned();
+                (name.to_string(), value)
+            })
+            .collect()
+    }
+
+    /// The `Age` header, if it's a valid number of seconds.
+    pub fn age(&self) -> Option<Duration> {
+        let value = self.headers.get(AGE)?.to_str().ok()?;
+        value.trim().parse().ok().map(Duration::from_secs)
+    }
+}
+
+/// Panics if response is not complete. The `headers` are cleared and reused,
+/// to keep their allocation.
+pub(crate) fn parts_from_complete_response<'headers, 'buf: 'headers>(
     response: Response<'headers, 'buf>,
+    mut headers: HeaderMap,
 ) -> ResponseParts {
     let status_code = response.code.unwrap();
-    let reason_phrase = response.reason.unwrap().to_string();
-    let mut headers = HeaderMap::new();
+    // `httparse` keeps any whitespace trailing the reason phrase, which is
+    // never meaningful.
+    let reason_phrase = response.reason.unwrap().trim_end().to_string();
+    headers.clear();
     for header in response.headers {
-        headers.insert(
+        headers.append(
             HeaderName::from_bytes(header.name.as_bytes()).unwrap(),
             HeaderValue::from_bytes(header.value).unwrap(),
         );
@@ -29,19 +114,185 @@ fn parts_from_complete_response<'headers, 'buf: 'headers>(
 }
 
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct HandshakeOutcome {
     pub response_parts: ResponseParts,
     pub data_after_handshake: Vec<u8>,
+    /// Whether the response head took more than one read to come in, so it
+    /// had to be collected in a carry-on buffer. If that's common, the read
+    /// buffer might be too small.
+    pub slow_path: bool,
 }
 
 impl HandshakeOutcome {
-    pub(crate) fn new<'headers, 'buf: 'headers>(
-        response: Response<'headers, 'buf>,
-        data_after_handshake: Vec<u8>,
-    ) -> Self {
-        Self {
-            response_parts: parts_from_complete_response(response),
-            data_after_handshake,
+    /// Whether some data arrived after the response head, i.e. the tunnel
+    /// has data to be read before anything from the stream.
+    pub fn has_trailing_data(&self) -> bool {
+        !self.data_after_handshake.is_empty()
+    }
+
+    /// Splits the data after the response head into the frames ending with
+    /// `delim`, which is dropped, and the incomplete remainder, which is to be
+    /// completed by further reads from the stream.
+    pub fn split_trailing(&self, delim: u8) -> (Vec<Vec<u8>>, Vec<u8>) {
+        let mut frames: Vec<Vec<u8>> = self
+            .data_after_handshake
+            .split(|&b| b == delim)
+            .map(Vec::from)
+            .collect();
+        // There's always at least one piece, the one after the last delimiter.
+        let remainder = frames.pop().unwrap_or_default();
+        (frames, remainder)
+    }
+
+    /// Wraps the `stream` the handshake went over, so that the data after the
+    /// response head is read from it first. The response parts are dropped.
+    pub fn into_stream<ARW>(self, stream: ARW) -> PrependIoStream<ARW>
+    where
+        ARW: AsyncRead + AsyncWrite + Unpin,
+    {
+        PrependIoStream::from_vec(stream, Some(self.data_after_handshake))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parts_with_headers(headers: &[(&'static str, &'static str)]) -> ResponseParts {
+        let mut map = HeaderMap::new();
+        for (name, value) in headers {
+            map.append(*name, HeaderValue::from_static(value));
+        }
+        ResponseParts {
+            status_code: 200,
+            reason_phrase: "OK".to_string(),
+            headers: map,
         }
     }
+
+    #[test]
+    fn split_trailing() {
+        let outcome = |data: &[u8]| HandshakeOutcome {
+            response_parts: parts_with_headers(&[]),
+            data_after_handshake: data.to_vec(),
+            slow_path: false,
+        };
+
+        let (frames, remainder) = outcome(b"partial").split_trailing(b'\n');
+        assert!(frames.is_empty());
+        assert_eq!(remainder, b"partial");
+
+        let (frames, remainder) = outcome(b"one\npartial").split_trailing(b'\n');
+        assert_eq!(frames, vec![b"one".to_vec()]);
+        assert_eq!(remainder, b"partial");
+
+        let (frames, remainder) = outcome(b"one\n\nthree\n").split_trailing(b'\n');
+        assert_eq!(frames, vec![b"one".to_vec(), vec![], b"three".to_vec()]);
+        assert!(remainder.is_empty());
+
+        let (frames, remainder) = outcome(b"").split_trailing(b'\n');
+        assert!(frames.is_empty());
+        assert!(remainder.is_empty());
+    }
+
+    #[test]
+    fn headers_as_strings() {
+        let mut parts = parts_with_headers(&[("proxy-agent", "test")]);
+        parts
+            .headers
+            .append("x-binary", HeaderValue::from_bytes(b"caf\xe9").unwrap());
+        assert_eq!(
+            parts.headers_as_strings(),
+            vec![
+                ("proxy-agent".to_string(), "test".to_string()),
+                ("x-binary".to_string(), "caf\u{fffd}".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn age() {
+        let parts = parts_with_headers(&[("age", "120")]);
+        assert_eq!(parts.age(), Some(Duration::from_secs(120)));
+
+        let parts = parts_with_headers(&[("age", "-1")]);
+        assert_eq!(parts.age(), None);
+
+        let parts = parts_with_headers(&[]);
+        assert_eq!(parts.age(), None);
+    }
+
+    #[cfg(feature = "httpdate")]
+    #[test]
+    fn date() {
+        use std::time::UNIX_EPOCH;
+
+        let parts = parts_with_headers(&[("date", "Sun, 06 Nov 1994 08:49:37 GMT")]);
+        assert_eq!(
+            parts.date(),
+            Some(UNIX_EPOCH + Duration::from_secs(784_111_777))
+        );
+
+        let parts = parts_with_headers(&[("date", "yesterday")]);
+        assert_eq!(parts.date(), None);
+    }
+
+    #[test]
+    fn suggests_upgrade_to_h2c() {
+        let parts =
+            parts_with_headers(&[("upgrade", "h2c"), ("connection", "keep-alive, Upgrade")]);
+        assert_eq!(parts.upgrade(), Some("h2c"));
+        assert!(parts.connection_upgrade());
+        assert_eq!(parts.suggests_upgrade(), Some("h2c"));
+
+        // Both headers are needed.
+        let parts = parts_with_headers(&[("upgrade", "h2c")]);
+        assert_eq!(parts.upgrade(), Some("h2c"));
+        assert_eq!(parts.suggests_upgrade(), None);
+
+        let parts = parts_with_headers(&[("connection", "upgrade")]);
+        assert_eq!(parts.upgrade(), None);
+        assert_eq!(parts.suggests_upgrade(), None);
+
+        let parts = parts_with_headers(&[("connection", "keep-alive")]);
+        assert!(!parts.connection_upgrade());
+    }
+
+    #[test]
+    fn clone_response_parts() {
+        let mut headers = HeaderMap::new();
+        headers.insert("x-custom", HeaderValue::from_static("Sample Value"));
+        let response_parts = ResponseParts {
+            status_code: 200,
+            reason_phrase: "Connection established".to_string(),
+            headers,
+        };
+
+        let cloned = response_parts.clone();
+        assert_eq!(cloned.status_code, response_parts.status_code);
+        assert_eq!(cloned.reason_phrase, response_parts.reason_phrase);
+        assert_eq!(cloned.headers, response_parts.headers);
+    }
+
+    #[test]
+    fn is_tunnel_established_test() {
+        let parts = |status_code, reason_phrase: &str| ResponseParts {
+            status_code,
+            reason_phrase: reason_phrase.to_string(),
+            headers: HeaderMap::new(),
+        };
+
+        let ok = parts(200, "OK");
+        assert!(ok.is_tunnel_established());
+        assert!(!ok.is_tunnel_established_strict());
+
+        let established = parts(200, "Connection established");
+        assert!(established.is_tunnel_established());
+        assert!(established.is_tunnel_established_strict());
+
+        let forbidden = parts(403, "Forbidden");
+        assert!(!forbidden.is_tunnel_established());
+        assert!(!forbidden.is_tunnel_established_strict());
+    }
 }
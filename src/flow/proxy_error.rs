@@ -0,0 +1,43 @@
+use super::ResponseParts;
+use std::borrow::Cow;
+use std::fmt;
+
+/// The proxy refused to establish the tunnel, responding with a non-2xx
+/// status.
+#[derive(Debug, Clone)]
+pub struct ProxyError {
+    pub response_parts: ResponseParts,
+    /// The decoded response body, if it was requested to be read, or a
+    /// snippet of it.
+    pub body: Option<Vec<u8>>,
+}
+
+impl ProxyError {
+    /// The body as text, with invalid UTF-8 replaced, for diagnostics.
+    pub fn body_text(&self) -> Option<Cow<'_, str>> {
+        self.body.as_deref().map(String::from_utf8_lossy)
+    }
+
+    /// Extracts the `ProxyError` from an I/O error, if that's what it wraps.
+    pub fn from_io(err: &std::io::Error) -> Option<&Self> {
+        err.get_ref().and_then(|err| err.downcast_ref())
+    }
+}
+
+impl fmt::Display for ProxyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "proxy responded with {} {}",
+            self.response_parts.status_code, self.response_parts.reason_phrase
+        )
+    }
+}
+
+impl std::error::Error for ProxyError {}
+
+impl From<ProxyError> for std::io::Error {
+    fn from(err: ProxyError) -> Self {
+        std::io::Error::other(err)
+    }
+}
@@ -1,34 +1,649 @@
-use crate::http::HeaderMap;
-use std::io::{Result, Write};
+use crate::authority::Authority;
+use crate::http::{HeaderName, HeaderValue, Method};
+use std::fmt;
+use std::io::{Error, ErrorKind, Result, Write};
 
-fn write_headers<W: Write>(writer: &mut W, map: &HeaderMap) -> Result<()> {
-    for (key, value) in map.iter() {
-        writer.write_all(key.as_str().as_bytes())?;
+/// The HTTP version token to emit in the request line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Version {
+    Http10,
+    #[default]
+    Http11,
+}
+
+impl Version {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            Version::Http10 => b"HTTP/1.0",
+            Version::Http11 => b"HTTP/1.1",
+        }
+    }
+}
+
+/// The line terminator to emit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    /// `\r\n`, as per the spec.
+    #[default]
+    CrLf,
+    /// A bare `\n`, for interop testing against lenient servers.
+    Lf,
+}
+
+impl LineEnding {
+    fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::CrLf => b"\r\n",
+            LineEnding::Lf => b"\n",
+        }
+    }
+}
+
+/// Tweaks to how the request is serialized. The defaults match [`write()`].
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Config {
+    /// Methods other than `CONNECT` get the target in the absolute form,
+    /// e.g. `http://example.com:80/`.
+    pub method: Method,
+    pub version: Version,
+    /// Emit the `Host` header with the target authority.
+    pub host_header: bool,
+    /// Leave the port out of the `Host` header when it's 80 or 443, for the
+    /// proxies that want it that way. The request target keeps it.
+    pub host_omit_default_port: bool,
+    /// The line terminator for the request line and the headers.
+    pub line_ending: LineEnding,
+    /// The initial capacity of the buffer the request is serialized into; it
+    /// grows as needed. By default, it's estimated from the headers.
+    pub write_buf_capacity: Option<usize>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            method: Method::CONNECT,
+            version: Version::default(),
+            host_header: true,
+            host_omit_default_port: false,
+            line_ending: LineEnding::default(),
+            write_buf_capacity: None,
+        }
+    }
+}
+
+/// Returns whether the headers included `Content-Length`. The headers are
+/// checked as per the `HeaderName` and `HeaderValue` rules, so that a value
+/// with a line break can't inject another header into the request.
+fn write_headers<W, I, K, V>(writer: &mut W, eol: &[u8], headers: I) -> Result<bool>
+where
+    W: Write,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    let mut has_content_length = false;
+    for (key, value) in headers {
+        let (key, value) = (key.as_ref(), value.as_ref());
+        check_header(key, value)?;
+        has_content_length |= key.eq_ignore_ascii_case("content-length");
+        writer.write_all(key.as_bytes())?;
         writer.write_all(b": ")?;
-        writer.write_all(value.as_bytes())?;
-        writer.write_all(b"\r\n")?;
+        writer.write_all(value)?;
+        writer.write_all(eol)?;
     }
-    Ok(())
+    Ok(has_content_length)
 }
 
-fn write_host_port<W: Write>(writer: &mut W, host: &str, port: u16) -> Result<()> {
-    writer.write_all(host.as_bytes())?;
-    writer.write_all(b":")?;
-    write!(writer, "{}", port)?;
+/// Fails with `InvalidInput` unless the `name` is a token, and the `value` has
+/// no control characters but tabs.
+fn check_header(name: &str, value: &[u8]) -> Result<()> {
+    let is_valid_name = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if !is_valid_name {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid header name {:?}", name),
+        ));
+    }
+    let is_valid_value = value
+        .iter()
+        .all(|&b| (b >= b' ' && b != 0x7f) || b == b'\t');
+    if !is_valid_value {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            format!("invalid value of the {} header", name),
+        ));
+    }
     Ok(())
 }
 
-pub fn write<W: Write>(writer: &mut W, host: &str, port: u16, headers: &HeaderMap) -> Result<()> {
-    writer.write_all(b"CONNECT ")?;
-    write_host_port(writer, host, port)?;
-    writer.write_all(b" HTTP/1.1\r\n")?;
+/// Formats the `host:port` authority. Bare IPv6 literals are bracketed, and
+/// the `%` separating a zone id is percent-encoded as `%25` (RFC 6874), so
+/// `fe80::1%eth0` becomes `[fe80::1%25eth0]`. Hosts that are already
+/// bracketed are written as is.
+pub(crate) struct HostPort<'a>(pub(crate) &'a str, pub(crate) u16);
+
+impl fmt::Display for HostPort<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", Host(self.0), self.1)
+    }
+}
+
+/// Formats just the host part of [`HostPort`].
+struct Host<'a>(&'a str);
+
+impl fmt::Display for Host<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let host = self.0;
+        if host.contains(':') && !host.starts_with('[') {
+            match host.split_once('%') {
+                Some((addr, zone)) => write!(f, "[{}%25{}]", addr, zone),
+                None => write!(f, "[{}]", host),
+            }
+        } else {
+            f.write_str(host)
+        }
+    }
+}
+
+/// The room taken by the request line, the `Host` and `Content-Length`
+/// headers and the terminator, on top of the two copies of the authority.
+const FIXED_OVERHEAD: usize = 128;
+
+/// Returns a buffer capacity that fits the request with the given headers
+/// without reallocating.
+pub fn capacity_hint<I, K, V>(host: &str, headers: I) -> usize
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    // Each header line adds `: ` and `\r\n` around the name and value.
+    let headers_len: usize = headers
+        .into_iter()
+        .map(|(key, value)| key.as_ref().len() + value.as_ref().len() + 4)
+        .sum();
+    // The authority may get brackets and an escaped zone id, and the port
+    // takes up to five digits.
+    let authority_len = host.len() + 10;
+    FIXED_OVERHEAD + 2 * authority_len + headers_len
+}
+
+/// Writes the request with the given headers, which can be supplied as a
+/// `&HeaderMap`, or as any other collection of name-value pairs. The headers
+/// that a `HeaderMap` wouldn't take are rejected with `InvalidInput`.
+pub fn write<W, I, K, V>(writer: &mut W, host: &str, port: u16, headers: I) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    write_with_config(writer, &Config::default(), host, port, headers)
+}
+
+/// Like [`write()`], but to the already validated `authority`.
+pub fn write_to<W, I, K, V>(writer: &mut W, authority: &Authority, headers: I) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    write(writer, authority.host(), authority.port(), headers)
+}
+
+pub fn write_with_config<W, I, K, V>(
+    writer: &mut W,
+    config: &Config,
+    host: &str,
+    port: u16,
+    headers: I,
+) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    write_with_body(writer, config, host, port, headers, &[])
+}
+
+/// Like [`write_with_config`], but appends the `body` after the head. Unless
+/// the headers already have it, `Content-Length` is added for a non-empty
+/// body, and for any body with methods other than `CONNECT`.
+pub fn write_with_body<W, I, K, V>(
+    writer: &mut W,
+    config: &Config,
+    host: &str,
+    port: u16,
+    headers: I,
+    body: &[u8],
+) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    // Some proxies hang on the requests to port 0, so fail early instead.
+    if port == 0 {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "port 0 is not a valid target",
+        ));
+    }
+    write_request(writer, config, Target::HostPort(host, port), headers, body)
+}
+
+/// Like [`write_with_config`], but with an arbitrary opaque `target` in place
+/// of the `host:port` authority, written as is, e.g. for the nonstandard
+/// proxies that accept `CONNECT /var/run/app.sock HTTP/1.1`.
+pub fn write_opaque<W, I, K, V>(
+    writer: &mut W,
+    config: &Config,
+    target: &str,
+    headers: I,
+) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    let is_valid = !target.is_empty() && target.bytes().all(|b| b.is_ascii_graphic());
+    if !is_valid {
+        return Err(Error::new(
+            ErrorKind::InvalidInput,
+            "the target must be non-empty and contain no whitespace or control characters",
+        ));
+    }
+    write_request(writer, config, Target::Opaque(target), headers, &[])
+}
+
+/// What the request is made to.
+#[derive(Clone, Copy)]
+enum Target<'a> {
+    HostPort(&'a str, u16),
+    Opaque(&'a str),
+}
+
+impl Target<'_> {
+    fn write<W: Write>(self, writer: &mut W) -> Result<()> {
+        match self {
+            Target::HostPort(host, port) => write!(writer, "{}", HostPort(host, port)),
+            Target::Opaque(target) => writer.write_all(target.as_bytes()),
+        }
+    }
+}
 
-    writer.write_all(b"Host: ")?;
-    write_host_port(writer, host, port)?;
-    writer.write_all(b"\r\n")?;
+fn write_request<W, I, K, V>(
+    writer: &mut W,
+    config: &Config,
+    target: Target<'_>,
+    headers: I,
+    body: &[u8],
+) -> Result<()>
+where
+    W: Write,
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    let is_connect = config.method == Method::CONNECT;
+    let is_absolute = !is_connect && matches!(target, Target::HostPort(..));
+    let eol = config.line_ending.as_bytes();
+    writer.write_all(config.method.as_str().as_bytes())?;
+    writer.write_all(b" ")?;
+    if is_absolute {
+        writer.write_all(b"http://")?;
+    }
+    target.write(writer)?;
+    if is_absolute {
+        writer.write_all(b"/")?;
+    }
+    writer.write_all(b" ")?;
+    writer.write_all(config.version.as_bytes())?;
+    writer.write_all(eol)?;
 
-    write_headers(writer, headers)?;
+    if config.host_header {
+        writer.write_all(b"Host: ")?;
+        match target {
+            Target::HostPort(host, 80 | 443) if config.host_omit_default_port => {
+                write!(writer, "{}", Host(host))?
+            }
+            target => target.write(writer)?,
+        }
+        writer.write_all(eol)?;
+    }
 
-    writer.write_all(b"\r\n")?;
+    let has_content_length = write_headers(writer, eol, headers)?;
+    if !has_content_length && (!is_connect || !body.is_empty()) {
+        write!(writer, "Content-Length: {}", body.len())?;
+        writer.write_all(eol)?;
+    }
+
+    writer.write_all(eol)?;
+    writer.write_all(body)?;
     Ok(())
 }
+
+/// Like [`write()`], but writes into a caller-provided `buf`, returning the
+/// number of bytes written. Fails with `WriteZero` if the request doesn't fit.
+pub fn write_to_slice<I, K, V>(buf: &mut [u8], host: &str, port: u16, headers: I) -> Result<usize>
+where
+    I: IntoIterator<Item = (K, V)>,
+    K: AsRef<str>,
+    V: AsRef<[u8]>,
+{
+    let capacity = buf.len();
+    let mut remaining = buf;
+    write(&mut remaining, host, port, headers)?;
+    Ok(capacity - remaining.len())
+}
+
+/// Like [`write()`], but emits the headers exactly in the supplied order.
+pub fn write_ordered<W: Write>(
+    writer: &mut W,
+    host: &str,
+    port: u16,
+    headers: &[(HeaderName, HeaderValue)],
+) -> Result<()> {
+    write(writer, host, port, headers.iter().map(|(k, v)| (k, v)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::HeaderMap;
+
+    #[test]
+    fn write_plain_headers() -> Result<()> {
+        let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                          Host: 127.0.0.1:8080\r\n\
+                          X-First: 1\r\n\
+                          X-Second: 2\r\n\
+                          \r\n";
+        let headers: Vec<(&str, &[u8])> = vec![("X-First", b"1"), ("X-Second", b"2")];
+        let mut buf = Vec::new();
+        write(&mut buf, "127.0.0.1", 8080, headers)?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_header_map() -> Result<()> {
+        let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                          Host: 127.0.0.1:8080\r\n\
+                          x-first: 1\r\n\
+                          \r\n";
+        let mut headers = HeaderMap::new();
+        headers.insert("X-First", HeaderValue::from_static("1"));
+        let mut buf = Vec::new();
+        write(&mut buf, "127.0.0.1", 8080, &headers)?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_repeated_header() -> Result<()> {
+        let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                          Host: 127.0.0.1:8080\r\n\
+                          via: 1.1 first\r\n\
+                          via: 1.1 second\r\n\
+                          x-other: 1\r\n\
+                          \r\n";
+        let mut headers = HeaderMap::new();
+        headers.append("Via", HeaderValue::from_static("1.1 first"));
+        headers.insert("X-Other", HeaderValue::from_static("1"));
+        headers.append("Via", HeaderValue::from_static("1.1 second"));
+        let mut buf = Vec::new();
+        write(&mut buf, "127.0.0.1", 8080, &headers)?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_without_host_header() -> Result<()> {
+        let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                          X-First: 1\r\n\
+                          \r\n";
+        let config = Config {
+            host_header: false,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        write_with_config(&mut buf, &config, "127.0.0.1", 8080, vec![("X-First", "1")])?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_host_omitting_default_port() -> Result<()> {
+        let mut buf = Vec::new();
+        write(&mut buf, "example.com", 443, vec![] as Vec<(&str, &str)>)?;
+        assert_eq!(
+            buf.as_slice(),
+            &b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com:443\r\n\r\n"[..]
+        );
+
+        let config = Config {
+            host_omit_default_port: true,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        write_with_config(
+            &mut buf,
+            &config,
+            "example.com",
+            443,
+            vec![] as Vec<(&str, &str)>,
+        )?;
+        assert_eq!(
+            buf.as_slice(),
+            &b"CONNECT example.com:443 HTTP/1.1\r\nHost: example.com\r\n\r\n"[..]
+        );
+
+        // Other ports are kept, and IPv6 hosts are still bracketed.
+        let mut buf = Vec::new();
+        write_with_config(&mut buf, &config, "::1", 8443, vec![] as Vec<(&str, &str)>)?;
+        assert!(buf.ends_with(b"Host: [::1]:8443\r\n\r\n"));
+        let mut buf = Vec::new();
+        write_with_config(&mut buf, &config, "::1", 443, vec![] as Vec<(&str, &str)>)?;
+        assert!(buf.ends_with(b"Host: [::1]\r\n\r\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn write_ipv6_host() -> Result<()> {
+        let sample_res = "CONNECT [::1]:8080 HTTP/1.1\r\n\
+                          Host: [::1]:8080\r\n\
+                          \r\n";
+        let mut buf = Vec::new();
+        write(&mut buf, "::1", 8080, &HeaderMap::new())?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+
+        let mut buf = Vec::new();
+        write(&mut buf, "[::1]", 8080, &HeaderMap::new())?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_zoned_ipv6_host() -> Result<()> {
+        let sample_res = "CONNECT [fe80::1%25eth0]:443 HTTP/1.1\r\n\
+                          Host: [fe80::1%25eth0]:443\r\n\
+                          \r\n";
+        let mut buf = Vec::new();
+        write(&mut buf, "fe80::1%eth0", 443, &HeaderMap::new())?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn capacity_hint_fits_big_header_set() -> Result<()> {
+        let mut headers = HeaderMap::new();
+        for i in 0..64 {
+            let name = HeaderName::from_bytes(format!("x-header-{}", i).as_bytes()).unwrap();
+            let value = HeaderValue::from_str(&"v".repeat(100 + i)).unwrap();
+            headers.insert(name, value);
+        }
+
+        let hint = capacity_hint("fe80::1%eth0", &headers);
+        let mut buf = Vec::with_capacity(hint);
+        let capacity = buf.capacity();
+        write(&mut buf, "fe80::1%eth0", 65535, &headers)?;
+        assert_eq!(buf.capacity(), capacity);
+        Ok(())
+    }
+
+    #[test]
+    fn write_to_exact_slice() -> Result<()> {
+        let sample_res = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                          Host: 127.0.0.1:8080\r\n\
+                          X-First: 1\r\n\
+                          \r\n";
+        let mut buf = [0u8; 69];
+        assert_eq!(buf.len(), sample_res.len());
+        let written = write_to_slice(&mut buf, "127.0.0.1", 8080, vec![("X-First", "1")])?;
+        assert_eq!(written, sample_res.len());
+        assert_eq!(&buf[..], sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_to_undersized_slice() {
+        let mut buf = [0u8; 32];
+        let err = write_to_slice(&mut buf, "127.0.0.1", 8080, vec![("X-First", "1")]).unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::WriteZero);
+    }
+
+    #[test]
+    fn write_post_with_body() -> Result<()> {
+        let sample_res = "POST http://127.0.0.1:8080/ HTTP/1.1\r\n\
+                          Host: 127.0.0.1:8080\r\n\
+                          X-First: 1\r\n\
+                          Content-Length: 11\r\n\
+                          \r\n\
+                          hello world";
+        let config = Config {
+            method: Method::POST,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        write_with_body(
+            &mut buf,
+            &config,
+            "127.0.0.1",
+            8080,
+            vec![("X-First", "1")],
+            b"hello world",
+        )?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_body_keeps_user_content_length() -> Result<()> {
+        let sample_res = "POST http://127.0.0.1:8080/ HTTP/1.1\r\n\
+                          Host: 127.0.0.1:8080\r\n\
+                          content-length: 5\r\n\
+                          \r\n\
+                          hello";
+        let config = Config {
+            method: Method::POST,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        let headers = vec![("content-length", "5")];
+        write_with_body(&mut buf, &config, "127.0.0.1", 8080, headers, b"hello")?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_line_endings() -> Result<()> {
+        let crlf = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                    Host: 127.0.0.1:8080\r\n\
+                    X-First: 1\r\n\
+                    \r\n";
+        let mut buf = Vec::new();
+        let config = Config::default();
+        write_with_config(&mut buf, &config, "127.0.0.1", 8080, vec![("X-First", "1")])?;
+        assert_eq!(buf.as_slice(), crlf.as_bytes());
+
+        let lf = "CONNECT 127.0.0.1:8080 HTTP/1.1\n\
+                  Host: 127.0.0.1:8080\n\
+                  X-First: 1\n\
+                  \n";
+        let mut buf = Vec::new();
+        let config = Config {
+            line_ending: LineEnding::Lf,
+            ..Default::default()
+        };
+        write_with_config(&mut buf, &config, "127.0.0.1", 8080, vec![("X-First", "1")])?;
+        assert_eq!(buf.as_slice(), lf.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_opaque_target() -> Result<()> {
+        let sample_res = "CONNECT /var/run/app.sock HTTP/1.1\r\n\
+                          X-First: 1\r\n\
+                          \r\n";
+        let config = Config {
+            host_header: false,
+            ..Default::default()
+        };
+        let mut buf = Vec::new();
+        write_opaque(
+            &mut buf,
+            &config,
+            "/var/run/app.sock",
+            vec![("X-First", "1")],
+        )?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+
+        let headers: Vec<(&str, &str)> = vec![];
+        let err = write_opaque(&mut buf, &config, "/bad path", headers).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        Ok(())
+    }
+
+    #[test]
+    fn write_to_authority() -> Result<()> {
+        let sample_res = "CONNECT [fe80::1%25eth0]:443 HTTP/1.1\r\n\
+                          Host: [fe80::1%25eth0]:443\r\n\
+                          \r\n";
+        let authority = Authority::new("fe80::1%eth0", 443)?;
+        let headers: Vec<(&str, &str)> = vec![];
+        let mut buf = Vec::new();
+        write_to(&mut buf, &authority, headers)?;
+        assert_eq!(buf.as_slice(), sample_res.as_bytes());
+        Ok(())
+    }
+
+    #[test]
+    fn write_rejects_invalid_headers() {
+        for headers in &[
+            vec![("X-First", &b"x\r\nInjected: 1"[..])],
+            vec![("X-First", &b"x\nInjected: 1"[..])],
+            vec![("X-First", &b"x\0"[..])],
+            vec![("X First", &b"1"[..])],
+            vec![("X-First:", &b"1"[..])],
+            vec![("X-First\r\nInjected", &b"1"[..])],
+            vec![("", &b"1"[..])],
+        ] {
+            let mut buf = Vec::new();
+            let err = write(&mut buf, "127.0.0.1", 8080, headers.clone()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput, "{:?}", headers);
+        }
+
+        let mut buf = Vec::new();
+        let headers: Vec<(&str, &[u8])> = vec![("X-First", b"tab\tand \xff")];
+        write(&mut buf, "127.0.0.1", 8080, headers).unwrap();
+    }
+}
@@ -0,0 +1,83 @@
+use super::ResponseParts;
+use std::io::Result;
+
+/// The most headers the response can have; the ones with more are rejected.
+pub const MAX_HEADERS: usize = 16;
+
+/// The default for [`Config::min_read_buf_len`], which doesn't reject any
+/// read buffers.
+pub const DEFAULT_MIN_READ_BUF_LEN: usize = 0;
+
+/// The default for [`Config::read_buf_len`].
+pub const DEFAULT_READ_BUF_LEN: usize = 1024;
+
+/// The default for [`Config::max_reason_len`].
+pub const DEFAULT_MAX_REASON_LEN: usize = 1024;
+
+/// Tweaks to how the response is received and parsed. The defaults match
+/// [`receive_response`](super::receive_response).
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub struct Config {
+    /// Read buffers smaller than this are rejected with `InvalidInput`, as
+    /// they'd have the response trickle in via lots of tiny reads. Off by
+    /// default; something like 16 bytes catches the obvious mistakes.
+    pub min_read_buf_len: usize,
+    /// The size of the read buffer, for the functions that allocate it
+    /// themselves, like [`handshake_sized`](super::handshake_sized).
+    pub read_buf_len: usize,
+    /// Accept data arriving right after the response head, before the tunnel
+    /// is in use. With strict CONNECT semantics that's a protocol violation,
+    /// reported as `InvalidData`.
+    pub allow_early_data: bool,
+    /// Reject a 2xx response that announces a body with a non-zero
+    /// `Content-Length` or a `Transfer-Encoding`, as a successful tunnel
+    /// shouldn't have one, and the proxy might be injecting data.
+    pub reject_success_body: bool,
+    /// Reject the headers continued on the next line with leading whitespace,
+    /// the obsolete line folding, with a dedicated error. `httparse` rejects
+    /// them too, but only as an invalid header name.
+    pub reject_obs_fold: bool,
+    /// Accept the lines of the head that end with a bare `\n`, as sent by
+    /// some non-compliant proxies, instead of rejecting them with
+    /// `InvalidData`.
+    pub allow_bare_lf: bool,
+    /// Responses with a longer reason phrase are rejected with `InvalidData`,
+    /// so that the proxy can't have an arbitrary amount of it kept around.
+    /// The status line is checked as it comes in, before it's complete.
+    pub max_reason_len: usize,
+    /// Always go through the carry-on buffer, as if the head didn't come in
+    /// with the first read. Only meant for testing that code path.
+    #[doc(hidden)]
+    pub force_carry_on: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            min_read_buf_len: DEFAULT_MIN_READ_BUF_LEN,
+            read_buf_len: DEFAULT_READ_BUF_LEN,
+            allow_early_data: true,
+            reject_success_body: false,
+            reject_obs_fold: false,
+            allow_bare_lf: false,
+            max_reason_len: DEFAULT_MAX_REASON_LEN,
+            force_carry_on: false,
+        }
+    }
+}
+
+/// Parses the response head out of the received bytes. Implement this to
+/// swap in a stricter or more permissive parser; the [`Config`] implements it
+/// with `httparse`, and that's what the handshake uses by default.
+pub trait ResponseParser {
+    /// Returns the parts of the response head along with the number of bytes
+    /// it took up, or `None` if more data is needed to complete it.
+    fn parse(&self, buf: &[u8]) -> Result<Option<(ResponseParts, usize)>>;
+}
+
+impl ResponseParser for Config {
+    fn parse(&self, buf: &[u8]) -> Result<Option<(ResponseParts, usize)>> {
+        super::parse_head(buf, self)
+    }
+}
@@ -0,0 +1,434 @@
+//! Timing out the handshake, with the runtime's timer supplied by the user.
+
+use super::{handshake, receive_response, send_request, HandshakeOutcome};
+use crate::http::HeaderMap;
+use futures_io::{AsyncRead, AsyncWrite};
+use std::future::Future;
+use std::io::{Error, ErrorKind, Result};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+
+/// The runtime's timer, e.g. `tokio::time::sleep` or
+/// `async_std::task::sleep`.
+pub trait Timer {
+    type Sleep: Future<Output = ()>;
+
+    fn sleep(&self, duration: Duration) -> Self::Sleep;
+}
+
+/// The handshake time limits, none by default.
+#[derive(Debug, Clone, Copy, Default)]
+#[non_exhaustive]
+pub struct Timeouts {
+    /// How long to wait for the response to start coming in after the
+    /// request is sent, to detect a proxy that accepts the connection but
+    /// stalls.
+    pub first_byte: Option<Duration>,
+    /// How long sending the request can take, to detect a proxy that isn't
+    /// reading it, e.g. over a half-open connection.
+    pub write: Option<Duration>,
+    /// How long the whole handshake can take.
+    pub total: Option<Duration>,
+}
+
+/// Like [`handshake`], but fails with `TimedOut` when running past the
+/// `timeouts`.
+pub async fn handshake_with_timeouts<ARW, T>(
+    stream: &mut ARW,
+    timer: &T,
+    timeouts: &Timeouts,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+    T: Timer,
+{
+    let mut stream = Timed::new(stream, timer, timeouts);
+    handshake(&mut stream, host, port, request_headers, read_buf).await
+}
+
+/// Like [`send_request`], but fails with `TimedOut` when running past the
+/// `write` or `total` of the `timeouts`.
+pub async fn send_request_with_timeouts<AW, T>(
+    stream: &mut AW,
+    timer: &T,
+    timeouts: &Timeouts,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+) -> Result<()>
+where
+    AW: AsyncWrite + Unpin,
+    T: Timer,
+{
+    let mut stream = Timed::new(stream, timer, timeouts);
+    send_request(&mut stream, host, port, request_headers).await
+}
+
+/// Like [`receive_response`], but fails with `TimedOut` once the `deadline`
+/// passes, e.g. the one for the whole operation the handshake is a part of.
+pub async fn receive_response_until<AR, T>(
+    stream: &mut AR,
+    timer: &T,
+    deadline: Instant,
+    read_buf: &mut [u8],
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+    T: Timer,
+{
+    let total = deadline.saturating_duration_since(Instant::now());
+    if total == Duration::ZERO {
+        return Err(Error::new(ErrorKind::TimedOut, "handshake timed out"));
+    }
+    let timeouts = Timeouts {
+        total: Some(total),
+        ..Default::default()
+    };
+    let mut stream = Timed::new(stream, timer, &timeouts);
+    receive_response(&mut stream, read_buf).await
+}
+
+/// Fails the I/O on the `stream` once the timeouts fire.
+struct Timed<'a, S, T: Timer> {
+    stream: &'a mut S,
+    timer: &'a T,
+    /// Taken once the first read starts, i.e. after the request is sent.
+    first_byte_timeout: Option<Duration>,
+    first_byte: Option<Pin<Box<T::Sleep>>>,
+    /// Taken once the first write starts.
+    write_timeout: Option<Duration>,
+    write: Option<Pin<Box<T::Sleep>>>,
+    total: Option<Pin<Box<T::Sleep>>>,
+}
+
+impl<'a, S, T: Timer> Timed<'a, S, T> {
+    fn new(stream: &'a mut S, timer: &'a T, timeouts: &Timeouts) -> Self {
+        Self {
+            stream,
+            timer,
+            first_byte_timeout: timeouts.first_byte,
+            first_byte: None,
+            write_timeout: timeouts.write,
+            write: None,
+            total: timeouts.total.map(|total| Box::pin(timer.sleep(total))),
+        }
+    }
+
+    fn poll_total(&mut self, cx: &mut Context<'_>) -> Result<()> {
+        if let Some(total) = &mut self.total {
+            if total.as_mut().poll(cx).is_ready() {
+                return Err(Error::new(ErrorKind::TimedOut, "handshake timed out"));
+            }
+        }
+        Ok(())
+    }
+
+    fn start_write(&mut self) {
+        if let Some(timeout) = self.write_timeout.take() {
+            self.write = Some(Box::pin(self.timer.sleep(timeout)));
+        }
+    }
+
+    fn check_write<R>(&mut self, cx: &mut Context<'_>, poll: Poll<Result<R>>) -> Poll<Result<R>> {
+        if let (Poll::Pending, Some(write)) = (&poll, &mut self.write) {
+            if write.as_mut().poll(cx).is_ready() {
+                return Poll::Ready(Err(Error::new(
+                    ErrorKind::TimedOut,
+                    "the proxy isn't taking the request",
+                )));
+            }
+        }
+        poll
+    }
+}
+
+impl<S, T> AsyncRead for Timed<'_, S, T>
+where
+    S: AsyncRead + Unpin,
+    T: Timer,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        this.poll_total(cx)?;
+
+        // The request is sent by now.
+        this.write = None;
+        if let Some(timeout) = this.first_byte_timeout.take() {
+            this.first_byte = Some(Box::pin(this.timer.sleep(timeout)));
+        }
+        let poll = Pin::new(&mut *this.stream).poll_read(cx, buf);
+        match (&poll, &mut this.first_byte) {
+            (Poll::Ready(_), first_byte) => *first_byte = None,
+            (Poll::Pending, Some(first_byte)) => {
+                if first_byte.as_mut().poll(cx).is_ready() {
+                    return Poll::Ready(Err(Error::new(
+                        ErrorKind::TimedOut,
+                        "no response from the proxy",
+                    )));
+                }
+            }
+            (Poll::Pending, None) => {}
+        }
+        poll
+    }
+}
+
+impl<S, T> AsyncWrite for Timed<'_, S, T>
+where
+    S: AsyncWrite + Unpin,
+    T: Timer,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        this.poll_total(cx)?;
+        this.start_write();
+        let poll = Pin::new(&mut *this.stream).poll_write(cx, buf);
+        this.check_write(cx, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        this.poll_total(cx)?;
+        this.start_write();
+        let poll = Pin::new(&mut *this.stream).poll_flush(cx);
+        this.check_write(cx, poll)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        this.poll_total(cx)?;
+        Pin::new(&mut *this.stream).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor;
+    use std::collections::VecDeque;
+
+    /// A timer with the virtual clock ticking on every poll: the sleep for
+    /// `n` milliseconds completes on the `n`th poll.
+    struct PollTimer;
+
+    struct PollSleep(u128);
+
+    impl Future for PollSleep {
+        type Output = ();
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+            self.0 = self.0.saturating_sub(1);
+            if self.0 == 0 {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        }
+    }
+
+    impl Timer for PollTimer {
+        type Sleep = PollSleep;
+
+        fn sleep(&self, duration: Duration) -> PollSleep {
+            PollSleep(duration.as_millis())
+        }
+    }
+
+    /// A stream that stalls for the given number of polls before each chunk,
+    /// and before taking each byte of the writes.
+    struct StallingStream {
+        chunks: VecDeque<(usize, &'static [u8])>,
+        write_stalls: usize,
+        stalled: usize,
+    }
+
+    impl AsyncRead for StallingStream {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut [u8],
+        ) -> Poll<Result<usize>> {
+            let (stalls, chunk) = match self.chunks.front_mut() {
+                Some(front) => front,
+                None => return Poll::Ready(Ok(0)),
+            };
+            if *stalls > 0 {
+                *stalls -= 1;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            let n = buf.len().min(chunk.len());
+            buf[..n].copy_from_slice(&chunk[..n]);
+            *chunk = &chunk[n..];
+            if chunk.is_empty() {
+                self.chunks.pop_front();
+            }
+            Poll::Ready(Ok(n))
+        }
+    }
+
+    impl AsyncWrite for StallingStream {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<Result<usize>> {
+            if self.write_stalls == 0 {
+                return Poll::Ready(Ok(buf.len()));
+            }
+            if self.stalled < self.write_stalls {
+                self.stalled += 1;
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            }
+            self.stalled = 0;
+            Poll::Ready(Ok(buf.len().min(1)))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    const TIMEOUTS: Timeouts = Timeouts {
+        first_byte: Some(Duration::from_millis(5)),
+        write: Some(Duration::from_millis(5)),
+        total: Some(Duration::from_millis(20)),
+    };
+
+    async fn run(chunks: Vec<(usize, &'static [u8])>) -> Result<HandshakeOutcome> {
+        let mut stream = StallingStream {
+            chunks: chunks.into(),
+            write_stalls: 0,
+            stalled: 0,
+        };
+        let mut read_buf = [0u8; 1024];
+        let headers = HeaderMap::new();
+        handshake_with_timeouts(
+            &mut stream,
+            &PollTimer,
+            &TIMEOUTS,
+            "127.0.0.1",
+            8080,
+            &headers,
+            &mut read_buf,
+        )
+        .await
+    }
+
+    #[test]
+    fn within_timeouts() -> Result<()> {
+        executor::block_on(async {
+            let outcome = run(vec![(2, b"HTTP/1.1 200"), (10, b" OK\r\n\r\n")]).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn first_byte_timeout() {
+        executor::block_on(async {
+            let err = run(vec![(10, b"HTTP/1.1 200 OK\r\n\r\n")])
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert_eq!(err.to_string(), "no response from the proxy");
+        })
+    }
+
+    #[test]
+    fn total_timeout() {
+        executor::block_on(async {
+            let err = run(vec![(0, b"HTTP/1.1 200"), (30, b" OK\r\n\r\n")])
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert_eq!(err.to_string(), "handshake timed out");
+        })
+    }
+
+    #[test]
+    fn write_timeout() -> Result<()> {
+        executor::block_on(async {
+            let headers = HeaderMap::new();
+            let timeouts = Timeouts {
+                write: Some(Duration::from_millis(10)),
+                ..Default::default()
+            };
+
+            // A byte per a couple of polls is too slow for the whole request.
+            let mut stream = StallingStream {
+                chunks: VecDeque::new(),
+                write_stalls: 2,
+                stalled: 0,
+            };
+            let err = send_request_with_timeouts(
+                &mut stream,
+                &PollTimer,
+                &timeouts,
+                "127.0.0.1",
+                8080,
+                &headers,
+            )
+            .await
+            .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert_eq!(err.to_string(), "the proxy isn't taking the request");
+
+            let mut stream = StallingStream {
+                chunks: VecDeque::new(),
+                write_stalls: 0,
+                stalled: 0,
+            };
+            send_request_with_timeouts(
+                &mut stream,
+                &PollTimer,
+                &timeouts,
+                "127.0.0.1",
+                8080,
+                &headers,
+            )
+            .await
+        })
+    }
+
+    #[test]
+    fn deadline() -> Result<()> {
+        executor::block_on(async {
+            let mut read_buf = [0u8; 1024];
+
+            // Already passed by the time the response is awaited, so the
+            // stream isn't even polled.
+            let deadline = Instant::now();
+            let mut stream = StallingStream {
+                chunks: vec![(0, &b"HTTP/1.1 200 OK\r\n\r\n"[..])].into(),
+                write_stalls: 0,
+                stalled: 0,
+            };
+            let err = receive_response_until(&mut stream, &PollTimer, deadline, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::TimedOut);
+            assert_eq!(stream.chunks.len(), 1);
+
+            let deadline = Instant::now() + Duration::from_secs(60);
+            let outcome =
+                receive_response_until(&mut stream, &PollTimer, deadline, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,167 @@
+//! Receiving the response from `tokio` streams into uninitialized memory, so
+//! that large read buffers don't have to be zeroed.
+
+use super::{check_received, receive, response, HandshakeOutcome, HeadReader, Reading};
+use std::io::{ErrorKind, Result};
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use tokio::io::{AsyncRead, ReadBuf};
+
+/// Like [`receive_response`](super::receive_response), but for `tokio`
+/// streams, with the `read_buf` that doesn't need to be initialized.
+pub async fn receive_response<AR>(
+    stream: &mut AR,
+    read_buf: &mut [MaybeUninit<u8>],
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+{
+    receive_response_with_config(stream, &response::Config::default(), read_buf).await
+}
+
+/// Like [`receive_response_with_config`](super::receive_response_with_config),
+/// but for `tokio` streams, with the `read_buf` that doesn't need to be
+/// initialized.
+pub async fn receive_response_with_config<AR>(
+    stream: &mut AR,
+    config: &response::Config,
+    read_buf: &mut [MaybeUninit<u8>],
+) -> Result<HandshakeOutcome>
+where
+    AR: AsyncRead + Unpin,
+{
+    let mut reader = UninitReader {
+        stream,
+        buf: ReadBuf::uninit(read_buf),
+    };
+    let outcome = receive(&mut reader, config, Reading::new(config), &[]).await?;
+    check_received(outcome, config)
+}
+
+/// Reads from a `tokio` stream into an uninitialized buffer. The data is only
+/// ever taken from the `ReadBuf`, so it's initialized even if the stream swaps
+/// the `ReadBuf` for another one.
+struct UninitReader<'a, AR> {
+    stream: &'a mut AR,
+    buf: ReadBuf<'a>,
+}
+
+impl<AR> HeadReader for UninitReader<'_, AR>
+where
+    AR: AsyncRead + Unpin,
+{
+    fn buf_len(&self) -> usize {
+        self.buf.capacity()
+    }
+
+    fn poll_read_part(&mut self, cx: &mut Context<'_>) -> Poll<Result<usize>> {
+        self.buf.clear();
+        loop {
+            match Pin::new(&mut *self.stream).poll_read(cx, &mut self.buf) {
+                Poll::Ready(Err(err)) if err.kind() == ErrorKind::Interrupted => continue,
+                Poll::Ready(result) => {
+                    return Poll::Ready(result.map(|()| self.buf.filled().len()))
+                }
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+    }
+
+    fn part(&self, len: usize) -> &[u8] {
+        &self.buf.filled()[..len]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::executor;
+
+    #[test]
+    fn receive_into_uninit_buf() -> Result<()> {
+        executor::block_on(async {
+            let mut socket: &[u8] = b"HTTP/1.1 200 OK\r\nProxy-Agent: test\r\n\r\ndata";
+            let mut read_buf = [MaybeUninit::uninit(); 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.response_parts.headers["proxy-agent"], "test");
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"data");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_over_many_reads_into_uninit_buf() -> Result<()> {
+        executor::block_on(async {
+            let mut socket: &[u8] = b"HTTP/1.1 100 Continue\r\n\r\n\
+                                     HTTP/1.1 200 Connection established\r\n\r\n";
+            let mut read_buf = [MaybeUninit::uninit(); 16];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert!(outcome.slow_path);
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(
+                outcome.response_parts.reason_phrase,
+                "Connection established"
+            );
+            assert!(socket.is_empty());
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_from_swapping_reader() -> Result<()> {
+        /// Swaps the `ReadBuf` for its own, leaving the caller's buffer as is.
+        struct Swapping(&'static [u8]);
+
+        impl AsyncRead for Swapping {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                _cx: &mut Context<'_>,
+                buf: &mut ReadBuf<'_>,
+            ) -> Poll<Result<()>> {
+                let data = std::mem::take(&mut self.0);
+                *buf = ReadBuf::new(Box::leak(data.to_vec().into_boxed_slice()));
+                buf.set_filled(data.len());
+                Poll::Ready(Ok(()))
+            }
+        }
+
+        executor::block_on(async {
+            let mut socket = Swapping(b"HTTP/1.1 200 OK\r\n\r\ndata");
+            let mut read_buf = [MaybeUninit::uninit(); 1024];
+            let outcome = receive_response(&mut socket, &mut read_buf).await?;
+            assert_eq!(outcome.response_parts.status_code, 200);
+            assert_eq!(outcome.data_after_handshake.as_slice(), b"data");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn receive_with_config_into_uninit_buf() {
+        executor::block_on(async {
+            let config = response::Config {
+                allow_early_data: false,
+                ..Default::default()
+            };
+            let mut socket: &[u8] = b"HTTP/1.1 200 OK\r\n\r\nearly";
+            let mut read_buf = [MaybeUninit::uninit(); 1024];
+            let err = receive_response_with_config(&mut socket, &config, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        })
+    }
+
+    #[test]
+    fn receive_unexpected_eof() {
+        executor::block_on(async {
+            let mut socket: &[u8] = b"HTTP/1.1 200 OK\r\n";
+            let mut read_buf = [MaybeUninit::uninit(); 1024];
+            let err = receive_response(&mut socket, &mut read_buf)
+                .await
+                .unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+        })
+    }
+}
@@ -0,0 +1,182 @@
+//! Building the `Forwarded` header (RFC 7239) for the proxy chains.
+
+use crate::http::{HeaderMap, HeaderValue};
+use ::http::header::FORWARDED;
+use std::io::{Error, ErrorKind, Result};
+
+/// Builds the `Forwarded` header value, one element per hop.
+///
+/// ```
+/// # use http_proxy_client_async::forwarded::Forwarded;
+/// let value = Forwarded::new()
+///     .forwarded_for("192.0.2.43")
+///     .proto("http")
+///     .next_hop()
+///     .forwarded_for("[2001:db8:cafe::17]")
+///     .to_header_value()
+///     .unwrap();
+/// assert_eq!(value, r#"for=192.0.2.43;proto=http, for="[2001:db8:cafe::17]""#);
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct Forwarded {
+    elements: Vec<Vec<(&'static str, String)>>,
+}
+
+impl Forwarded {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The client that made the request to the proxy. IPv6 addresses have to
+    /// be bracketed.
+    pub fn forwarded_for(self, node: &str) -> Self {
+        self.param("for", node)
+    }
+
+    /// The interface where the request came in to the proxy.
+    pub fn by(self, node: &str) -> Self {
+        self.param("by", node)
+    }
+
+    /// The `Host` header as received by the proxy.
+    pub fn host(self, host: &str) -> Self {
+        self.param("host", host)
+    }
+
+    /// The protocol used to make the request, e.g. `http` or `https`.
+    pub fn proto(self, proto: &str) -> Self {
+        self.param("proto", proto)
+    }
+
+    /// Starts the element for the next hop; the following parameters go
+    /// there.
+    pub fn next_hop(mut self) -> Self {
+        self.elements.push(Vec::new());
+        self
+    }
+
+    fn param(mut self, name: &'static str, value: &str) -> Self {
+        if self.elements.is_empty() {
+            self.elements.push(Vec::new());
+        }
+        let element = self.elements.last_mut().unwrap();
+        element.push((name, value.to_string()));
+        self
+    }
+
+    /// Values that aren't tokens are quoted. Fails with `InvalidInput` if
+    /// some value can't be put in a header.
+    pub fn to_header_value(&self) -> Result<HeaderValue> {
+        let mut value = String::new();
+        for element in self.elements.iter().filter(|element| !element.is_empty()) {
+            if !value.is_empty() {
+                value.push_str(", ");
+            }
+            for (i, (name, param)) in element.iter().enumerate() {
+                if i != 0 {
+                    value.push(';');
+                }
+                value.push_str(name);
+                value.push('=');
+                push_token_or_quoted(&mut value, param);
+            }
+        }
+        HeaderValue::from_str(&value).map_err(|err| Error::new(ErrorKind::InvalidInput, err))
+    }
+
+    /// Inserts the header into `headers`. If there's a `Forwarded` header
+    /// already, i.e. from the previous hops, the new elements are appended to
+    /// it.
+    pub fn insert_into(&self, headers: &mut HeaderMap) -> Result<()> {
+        let value = self.to_header_value()?;
+        let value = match headers.get(FORWARDED) {
+            Some(existing) => {
+                let mut combined = existing.as_bytes().to_vec();
+                combined.extend_from_slice(b", ");
+                combined.extend_from_slice(value.as_bytes());
+                HeaderValue::from_bytes(&combined)
+                    .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?
+            }
+            None => value,
+        };
+        headers.insert(FORWARDED, value);
+        Ok(())
+    }
+}
+
+fn push_token_or_quoted(buf: &mut String, value: &str) {
+    let is_token = !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    if is_token {
+        buf.push_str(value);
+        return;
+    }
+    buf.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            buf.push('\\');
+        }
+        buf.push(c);
+    }
+    buf.push('"');
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_element() -> Result<()> {
+        let value = Forwarded::new()
+            .forwarded_for("192.0.2.60")
+            .proto("http")
+            .by("203.0.113.43")
+            .host("example.com:8080")
+            .to_header_value()?;
+        assert_eq!(
+            value,
+            r#"for=192.0.2.60;proto=http;by=203.0.113.43;host="example.com:8080""#
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn chained_elements() -> Result<()> {
+        let mut headers = HeaderMap::new();
+        Forwarded::new()
+            .forwarded_for("192.0.2.43")
+            .insert_into(&mut headers)?;
+        Forwarded::new()
+            .forwarded_for("198.51.100.17")
+            .next_hop()
+            .forwarded_for("_hidden")
+            .by("unknown")
+            .insert_into(&mut headers)?;
+        assert_eq!(
+            headers[FORWARDED],
+            "for=192.0.2.43, for=198.51.100.17, for=_hidden;by=unknown"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn quotes_and_escapes() -> Result<()> {
+        let value = Forwarded::new()
+            .forwarded_for("[2001:db8:cafe::17]:4711")
+            .host("odd\"host")
+            .to_header_value()?;
+        assert_eq!(value, r#"for="[2001:db8:cafe::17]:4711";host="odd\"host""#);
+        Ok(())
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        let err = Forwarded::new()
+            .forwarded_for("bad\r\nvalue")
+            .to_header_value()
+            .unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+}
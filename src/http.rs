@@ -1 +1,2 @@
 pub use ::http::header::{HeaderMap, HeaderName, HeaderValue};
+pub use ::http::Method;
@@ -1,41 +1,505 @@
 #![warn(missing_debug_implementations, rust_2018_idioms)]
 
+/// Emits a `tracing` debug event, or nothing without the `tracing` feature.
+macro_rules! debug_event {
+    ($($arg:tt)*) => {
+        #[cfg(feature = "tracing")]
+        tracing::debug!($($arg)*);
+    };
+}
+
+pub mod auth;
+pub mod authority;
+pub mod crlf_writer;
 pub mod flow;
+pub mod forwarded;
 pub mod http;
+pub mod metered;
 pub mod prepend_io_stream;
+#[cfg(feature = "url")]
+pub mod proxy_url;
+#[cfg(feature = "tls")]
+pub mod tls;
 
+use ::http::header::PROXY_AUTHORIZATION;
 use futures_io::{AsyncRead, AsyncWrite};
+use std::future::Future;
 
 pub use crate::http::*;
-pub use flow::{HandshakeOutcome, ResponseParts};
+pub use authority::Authority;
+pub use flow::{HandshakeConfig, HandshakeOutcome, ProxyError, ResponseParts};
 pub use prepend_io_stream::PrependIoStream as Stream;
 pub use std::io::Result;
 
+/// Performs the handshake, and wraps the `stream` so that the data that came
+/// in right after the response head is read first. The `host` and `port`
+/// are validated as an [`Authority`], failing with `InvalidInput`.
 pub async fn handshake_and_wrap<ARW>(
+    stream: ARW,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<Outcome<Stream<ARW>>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = Authority::new(host, port)?;
+    handshake_and_wrap_to(stream, &authority, request_headers, read_buf).await
+}
+
+/// Like [`handshake_and_wrap`], but to the already validated `authority`.
+pub async fn handshake_and_wrap_to<ARW>(
+    stream: ARW,
+    authority: &Authority,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<Outcome<Stream<ARW>>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    handshake_and_wrap_or_return_to(stream, authority, request_headers, read_buf)
+        .await
+        .map_err(|(err, _)| err)
+}
+
+/// Like [`handshake_and_wrap`], but with the request and response tweaks.
+pub async fn handshake_and_wrap_with_config<ARW>(
+    stream: ARW,
+    config: &HandshakeConfig,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<Outcome<Stream<ARW>>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = Authority::new(host, port)?;
+    handshake_and_wrap_with_config_to(stream, config, &authority, request_headers, read_buf).await
+}
+
+/// Like [`handshake_and_wrap_with_config`], but to the already validated
+/// `authority`.
+pub async fn handshake_and_wrap_with_config_to<ARW>(
     mut stream: ARW,
+    config: &HandshakeConfig,
+    authority: &Authority,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<Outcome<Stream<ARW>>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let request = flow::send_request_captured(
+        &mut stream,
+        &config.request,
+        authority.host(),
+        authority.port(),
+        request_headers,
+    )
+    .await?;
+    let outcome =
+        flow::receive_response_with_config(&mut stream, &config.response, read_buf).await?;
+
+    let mut outcome =
+        Outcome::from_parts(outcome.response_parts, stream, outcome.data_after_handshake);
+    if config.capture_request {
+        outcome.request = Some(request);
+    }
+    Ok(outcome)
+}
+
+/// Like [`handshake_and_wrap`], but hands the `stream` back on error, so that
+/// the caller can decide whether to close or reuse it. Whatever was read off
+/// the stream before the error is lost.
+pub async fn handshake_and_wrap_or_return<ARW>(
+    stream: ARW,
     host: &str,
     port: u16,
     request_headers: &HeaderMap,
     read_buf: &mut [u8],
+) -> std::result::Result<Outcome<Stream<ARW>>, (std::io::Error, ARW)>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    match Authority::new(host, port) {
+        Ok(authority) => {
+            handshake_and_wrap_or_return_to(stream, &authority, request_headers, read_buf).await
+        }
+        Err(err) => Err((err, stream)),
+    }
+}
+
+/// Like [`handshake_and_wrap_or_return`], but to the already validated
+/// `authority`.
+pub async fn handshake_and_wrap_or_return_to<ARW>(
+    mut stream: ARW,
+    authority: &Authority,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> std::result::Result<Outcome<Stream<ARW>>, (std::io::Error, ARW)>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    match flow::handshake_to(&mut stream, authority, request_headers, read_buf).await {
+        Ok(outcome) => Ok(Outcome::from_parts(
+            outcome.response_parts,
+            stream,
+            outcome.data_after_handshake,
+        )),
+        Err(err) => Err((err, stream)),
+    }
+}
+
+/// Like [`handshake_and_wrap`], but treats a non-2xx response as a rejection,
+/// handing back the error along with the wrapped stream. The stream is
+/// positioned right after the response head, so that the caller can read the
+/// error body from it, or just reuse the connection.
+pub async fn try_handshake<ARW>(
+    stream: ARW,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<std::result::Result<Outcome<Stream<ARW>>, (ProxyError, Stream<ARW>)>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = Authority::new(host, port)?;
+    try_handshake_to(stream, &authority, request_headers, read_buf).await
+}
+
+/// Like [`try_handshake`], but to the already validated `authority`.
+pub async fn try_handshake_to<ARW>(
+    stream: ARW,
+    authority: &Authority,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<std::result::Result<Outcome<Stream<ARW>>, (ProxyError, Stream<ARW>)>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let config = HandshakeConfig::default();
+    try_handshake_with_config_to(stream, &config, authority, request_headers, read_buf).await
+}
+
+/// Like [`try_handshake`], but with the request and response tweaks, and
+/// the success decided by [`HandshakeConfig::is_success`].
+pub async fn try_handshake_with_config<ARW>(
+    stream: ARW,
+    config: &HandshakeConfig,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<std::result::Result<Outcome<Stream<ARW>>, (ProxyError, Stream<ARW>)>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = Authority::new(host, port)?;
+    try_handshake_with_config_to(stream, config, &authority, request_headers, read_buf).await
+}
+
+/// Like [`try_handshake_with_config`], but to the already validated
+/// `authority`.
+pub async fn try_handshake_with_config_to<ARW>(
+    stream: ARW,
+    config: &HandshakeConfig,
+    authority: &Authority,
+    request_headers: &HeaderMap,
+    read_buf: &mut [u8],
+) -> Result<std::result::Result<Outcome<Stream<ARW>>, (ProxyError, Stream<ARW>)>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let outcome =
+        handshake_and_wrap_with_config_to(stream, config, authority, request_headers, read_buf)
+            .await?;
+    if config.is_success(&outcome.response_parts) {
+        return Ok(Ok(outcome));
+    }
+    let err = ProxyError {
+        response_parts: outcome.response_parts,
+        body: None,
+    };
+    Ok(Err((err, outcome.stream)))
+}
+
+/// A hop in the proxy chain: the target of a `CONNECT` request along with the
+/// headers for it.
+#[derive(Debug, Clone)]
+pub struct Hop<'a> {
+    pub authority: Authority,
+    pub headers: &'a HeaderMap,
+}
+
+/// Tunnels through the chain of proxies. The `stream` is connected to the
+/// first proxy, and each of the `hops` is `CONNECT`ed to in turn through the
+/// tunnel established so far: all but the last are the next proxies, and the
+/// last one is the final target.
+///
+/// Stops at the first non-2xx response, returning it.
+pub async fn handshake_chain<ARW>(
+    stream: ARW,
+    hops: &[Hop<'_>],
+    read_buf: &mut [u8],
 ) -> Result<Outcome<Stream<ARW>>>
 where
     ARW: AsyncRead + AsyncWrite + Unpin,
 {
-    let HandshakeOutcome {
-        response_parts,
-        data_after_handshake,
-    } = flow::handshake(&mut stream, host, port, request_headers, read_buf).await?;
+    let config = HandshakeConfig::default();
+    handshake_chain_with_config(stream, &config, hops, read_buf).await
+}
+
+/// Like [`handshake_chain`], but with the request and response tweaks for
+/// every hop, and the success decided by [`HandshakeConfig::is_success`].
+pub async fn handshake_chain_with_config<ARW>(
+    stream: ARW,
+    config: &HandshakeConfig,
+    hops: &[Hop<'_>],
+    read_buf: &mut [u8],
+) -> Result<Outcome<Stream<ARW>>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let mut stream = Stream::plain(stream);
+    let mut response_parts = None;
+    for hop in hops {
+        let outcome = flow::handshake_with_config(
+            &mut stream,
+            config,
+            hop.authority.host(),
+            hop.authority.port(),
+            hop.headers,
+            read_buf,
+        )
+        .await?;
+        // The data after the head is either the next proxy's response, or
+        // the tunneled data, so it's put back to be read by whatever's next.
+        stream.unshift(&outcome.data_after_handshake);
 
+        let is_success = config.is_success(&outcome.response_parts);
+        response_parts = Some(outcome.response_parts);
+        if !is_success {
+            break;
+        }
+    }
+
+    let response_parts = response_parts.ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, "no hops to connect to")
+    })?;
     Ok(Outcome {
         response_parts,
-        stream: Stream::from_vec(stream, Some(data_after_handshake.into())),
+        trailing_len: stream.pending_prepend_data().len(),
+        stream,
+        request: None,
     })
 }
 
+/// Performs the handshake, and if the proxy rejects it with
+/// `407 Proxy Authentication Required` offering the `Basic` scheme, retries
+/// once over a fresh stream with the `Proxy-Authorization` header set.
+///
+/// The rejected stream can't be reused, so `connect` is invoked to produce a
+/// new stream for every attempt.
+pub async fn handshake_with_auth<C, F, ARW>(
+    connect: C,
+    host: &str,
+    port: u16,
+    request_headers: &HeaderMap,
+    username: &str,
+    password: &str,
+    read_buf: &mut [u8],
+) -> Result<Outcome<Stream<ARW>>>
+where
+    C: FnMut() -> F,
+    F: Future<Output = Result<ARW>>,
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let authority = Authority::new(host, port)?;
+    handshake_with_auth_to(
+        connect,
+        &authority,
+        request_headers,
+        username,
+        password,
+        read_buf,
+    )
+    .await
+}
+
+/// Like [`handshake_with_auth`], but to the already validated `authority`.
+pub async fn handshake_with_auth_to<C, F, ARW>(
+    mut connect: C,
+    authority: &Authority,
+    request_headers: &HeaderMap,
+    username: &str,
+    password: &str,
+    read_buf: &mut [u8],
+) -> Result<Outcome<Stream<ARW>>>
+where
+    C: FnMut() -> F,
+    F: Future<Output = Result<ARW>>,
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = connect().await?;
+    let outcome = handshake_and_wrap_to(stream, authority, request_headers, read_buf).await?;
+
+    let response_parts = &outcome.response_parts;
+    if response_parts.status_code != 407 || !auth::offers_basic(&response_parts.headers) {
+        return Ok(outcome);
+    }
+    drop(outcome);
+
+    let mut request_headers = request_headers.clone();
+    request_headers.insert(PROXY_AUTHORIZATION, auth::basic_auth(username, password));
+
+    let stream = connect().await?;
+    handshake_and_wrap_to(stream, authority, &request_headers, read_buf).await
+}
+
+/// Builds up the handshake: the request headers along with the request and
+/// response tweaks.
+#[derive(Debug, Clone, Default)]
+pub struct HandshakeBuilder {
+    config: HandshakeConfig,
+    headers: HeaderMap,
+}
+
+impl HandshakeBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn config(mut self, config: HandshakeConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    /// Adds a request header, keeping the ones with the same name.
+    pub fn header(mut self, name: HeaderName, value: HeaderValue) -> Self {
+        self.headers.append(name, value);
+        self
+    }
+
+    /// Sets the `Proxy-Authorization` header with the `Basic` credentials.
+    pub fn basic_auth(mut self, username: &str, password: &str) -> Self {
+        self.headers
+            .insert(PROXY_AUTHORIZATION, auth::basic_auth(username, password));
+        self
+    }
+
+    pub fn headers(&self) -> &HeaderMap {
+        &self.headers
+    }
+
+    /// Performs the handshake, see [`handshake_and_wrap_with_config`].
+    pub async fn handshake_and_wrap<ARW>(
+        &self,
+        stream: ARW,
+        host: &str,
+        port: u16,
+        read_buf: &mut [u8],
+    ) -> Result<Outcome<Stream<ARW>>>
+    where
+        ARW: AsyncRead + AsyncWrite + Unpin,
+    {
+        let authority = Authority::new(host, port)?;
+        self.handshake_and_wrap_to(stream, &authority, read_buf)
+            .await
+    }
+
+    /// Like [`handshake_and_wrap`](Self::handshake_and_wrap), but to the
+    /// already validated `authority`.
+    pub async fn handshake_and_wrap_to<ARW>(
+        &self,
+        stream: ARW,
+        authority: &Authority,
+        read_buf: &mut [u8],
+    ) -> Result<Outcome<Stream<ARW>>>
+    where
+        ARW: AsyncRead + AsyncWrite + Unpin,
+    {
+        handshake_and_wrap_with_config_to(stream, &self.config, authority, &self.headers, read_buf)
+            .await
+    }
+}
+
 #[derive(Debug)]
+#[non_exhaustive]
 pub struct Outcome<T> {
     pub response_parts: ResponseParts,
     pub stream: T,
+    /// The amount of data that arrived after the response head, and was put
+    /// in front of the `stream`.
+    pub trailing_len: usize,
+    /// The exact bytes of the request that was sent, if asked for with
+    /// [`HandshakeConfig::capture_request`].
+    pub request: Option<Vec<u8>>,
+}
+
+impl<ARW> Outcome<Stream<ARW>>
+where
+    ARW: AsyncRead + AsyncWrite + Unpin,
+{
+    /// Wraps a `stream` that went through the handshake elsewhere, e.g. in
+    /// some other library, with the `leftover` data that was read past the
+    /// response head, the same way [`handshake_and_wrap`] does.
+    pub fn from_parts(response_parts: ResponseParts, stream: ARW, leftover: Vec<u8>) -> Self {
+        let trailing_len = leftover.len();
+
+        // Skip the chain entirely if there's nothing to prepend.
+        let stream = if leftover.is_empty() {
+            Stream::plain(stream)
+        } else {
+            Stream::from_vec(stream, Some(leftover))
+        };
+        Self {
+            response_parts,
+            stream,
+            trailing_len,
+            request: None,
+        }
+    }
+
+    /// The data that arrived after the response head and wasn't read yet.
+    /// It's owned by the `stream`, which yields it before anything else.
+    pub fn leftover(&self) -> &[u8] {
+        self.stream.pending_prepend_data()
+    }
+
+    /// The inverse of [`from_parts`](Self::from_parts): unwraps the stream,
+    /// handing out the unread leftover as a standalone buffer, e.g. to be
+    /// forwarded elsewhere.
+    pub fn into_parts(self) -> (ResponseParts, ARW, Vec<u8>) {
+        let (stream, cursor) = self.stream.into_inner();
+        let leftover = match cursor {
+            Some(cursor) => {
+                let pos = cursor.position() as usize;
+                let mut leftover = cursor.into_inner();
+                leftover.drain(..pos);
+                leftover
+            }
+            None => Vec::new(),
+        };
+        (self.response_parts, stream, leftover)
+    }
+}
+
+impl<T> Outcome<T> {
+    /// Turns a non-2xx response into a [`ProxyError`], dropping the stream.
+    // The error is as large as the `Outcome` itself, so boxing it buys little.
+    #[allow(clippy::result_large_err)]
+    pub fn error_for_status(self) -> std::result::Result<Self, ProxyError> {
+        if self.response_parts.is_tunnel_established() {
+            return Ok(self);
+        }
+        Err(ProxyError {
+            response_parts: self.response_parts,
+            body: None,
+        })
+    }
 }
 
 impl<T> AsRef<T> for Outcome<T> {
@@ -43,3 +507,9 @@ impl<T> AsRef<T> for Outcome<T> {
         &self.stream
     }
 }
+
+impl<T> AsMut<T> for Outcome<T> {
+    fn as_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+}
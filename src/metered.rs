@@ -0,0 +1,139 @@
+//! Counting the traffic through the tunnel.
+
+use futures_io::{AsyncRead, AsyncWrite, IoSlice, IoSliceMut};
+use std::io::Result;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Counts the bytes read from and written to the inner stream, e.g. for the
+/// per-tunnel traffic stats.
+#[derive(Debug)]
+pub struct MeteredStream<S> {
+    inner: S,
+    bytes_read: u64,
+    bytes_written: u64,
+}
+
+impl<S> MeteredStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            bytes_read: 0,
+            bytes_written: 0,
+        }
+    }
+
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
+    }
+
+    pub fn bytes_written(&self) -> u64 {
+        self.bytes_written
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+/// Adds the bytes transferred by a successful `poll` to the `counter`.
+fn count(counter: &mut u64, poll: Poll<Result<usize>>) -> Poll<Result<usize>> {
+    if let Poll::Ready(Ok(n)) = poll {
+        *counter += n as u64;
+    }
+    poll
+}
+
+impl<S> AsyncRead for MeteredStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read(cx, buf);
+        count(&mut this.bytes_read, poll)
+    }
+
+    fn poll_read_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &mut [IoSliceMut<'_>],
+    ) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_read_vectored(cx, bufs);
+        count(&mut this.bytes_read, poll)
+    }
+}
+
+impl<S> AsyncWrite for MeteredStream<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write(cx, buf);
+        count(&mut this.bytes_written, poll)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut this.inner).poll_write_vectored(cx, bufs);
+        count(&mut this.bytes_written, poll)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_close(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prepend_io_stream::PrependIoStream;
+    use futures::{executor, io::Cursor, AsyncReadExt, AsyncWriteExt};
+    use merge_io::MergeIO;
+
+    #[test]
+    fn count_traffic() -> Result<()> {
+        executor::block_on(async {
+            let socket = MergeIO::new(Cursor::new(b"world".to_vec()), Cursor::new(vec![]));
+            let stream = PrependIoStream::from_vec(socket, Some(b"hello ".to_vec()));
+            let mut stream = MeteredStream::new(stream);
+
+            let mut data = String::new();
+            stream.read_to_string(&mut data).await?;
+            assert_eq!(data, "hello world");
+            stream.write_all(b"ping").await?;
+            stream.write_all(b"!").await?;
+
+            assert_eq!(stream.bytes_read(), 11);
+            assert_eq!(stream.bytes_written(), 5);
+            let (socket, _) = stream.into_inner().into_inner();
+            assert_eq!(socket.writer().get_ref().as_slice(), b"ping!");
+            Ok(())
+        })
+    }
+}
@@ -1,16 +1,22 @@
-use futures_io::{AsyncRead, AsyncWrite, IoSlice, IoSliceMut};
-use futures_util::io::{AsyncReadExt, Chain, Cursor};
-use std::io::Result;
+use futures_io::{AsyncRead, AsyncSeek, AsyncWrite, IoSlice, IoSliceMut};
+use futures_util::future;
+use futures_util::io::{AsyncReadExt, AsyncWriteExt, Chain, Cursor};
+use futures_util::sink::{Sink, SinkExt};
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result, SeekFrom};
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 #[derive(Debug)]
-pub enum PrependIoStream<T>
+pub struct PrependIoStream<T>
 where
     T: AsyncRead + AsyncWrite + Unpin,
 {
-    Chain(Chain<Cursor<Vec<u8>>, T>),
-    Plain(T),
+    stream: T,
+    /// The data to read before the stream's, empty if there's none.
+    prepend: Cursor<Vec<u8>>,
+    bytes_read: u64,
+    hold_writes: bool,
 }
 
 impl<T> PrependIoStream<T>
@@ -29,38 +35,261 @@ where
         }
     }
 
+    /// Like [`from_vec`](Self::from_vec), but rejects the prepend data longer
+    /// than `max_len` with `InvalidData`, guarding against a proxy that
+    /// streamed a lot of data right after the handshake.
+    pub fn from_vec_capped(
+        stream: T,
+        read_prepend: Option<Vec<u8>>,
+        max_len: usize,
+    ) -> Result<Self> {
+        let len = read_prepend.as_ref().map_or(0, Vec::len);
+        if len > max_len {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "{} bytes of prepend data exceed the maximum of {} bytes",
+                    len, max_len
+                ),
+            ));
+        }
+        Ok(Self::from_vec(stream, read_prepend))
+    }
+
     pub fn from_cursor(stream: T, read_prepend: Cursor<Vec<u8>>) -> Self {
-        Self::chain(read_prepend.chain(stream))
+        Self {
+            stream,
+            prepend: read_prepend,
+            bytes_read: 0,
+            hold_writes: false,
+        }
     }
 
     pub fn chain(chain: Chain<Cursor<Vec<u8>>, T>) -> Self {
-        PrependIoStream::Chain(chain)
+        let (read_prepend, stream) = chain.into_inner();
+        Self::from_cursor(stream, read_prepend)
     }
 
     pub fn plain(stream: T) -> Self {
-        PrependIoStream::Plain(stream)
+        Self::from_cursor(stream, Cursor::new(Vec::new()))
+    }
+
+    /// Makes the writes fail until all the prepend data is read, for the
+    /// strict request/response protocols where writing before the previous
+    /// response was consumed is a bug. Off by default.
+    pub fn set_hold_writes(&mut self, hold_writes: bool) {
+        self.hold_writes = hold_writes;
+    }
+
+    fn check_writable(&self) -> Result<()> {
+        if self.hold_writes && !self.pending_prepend_data().is_empty() {
+            return Err(Error::other(
+                "writes are held until the prepend data is read",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Whether the reads go straight to the inner stream, i.e. there's no
+    /// prepend data, not even the one that was already read.
+    pub fn is_plain(&self) -> bool {
+        self.prepend.get_ref().is_empty()
+    }
+
+    /// The total amount of data yielded by the reads so far, from both the
+    /// prepend data and the inner stream. The data that was peeked at isn't
+    /// counted until it's read.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read
     }
 
     pub fn into_inner(self) -> (T, Option<Cursor<Vec<u8>>>) {
-        match self {
-            PrependIoStream::Chain(chain) => {
-                let (cursor, stream) = chain.into_inner();
-                (stream, Some(cursor))
+        let prepend = if self.is_plain() {
+            None
+        } else {
+            Some(self.prepend)
+        };
+        (self.stream, prepend)
+    }
+
+    pub fn get_ref(&self) -> &T {
+        &self.stream
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.stream
+    }
+
+    /// Swaps the inner stream for the `new` one, returning the old one, e.g.
+    /// to upgrade it in place. The pending prepend data is kept in front.
+    pub fn replace_inner(&mut self, new: T) -> T {
+        std::mem::replace(&mut self.stream, new)
+    }
+
+    /// Puts `data` in front of the pending prepend data, so that it's read
+    /// again before anything else.
+    pub fn unshift(&mut self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        let pending = self.pending_prepend_data();
+        let mut prepend = Vec::with_capacity(data.len() + pending.len());
+        prepend.extend_from_slice(data);
+        prepend.extend_from_slice(pending);
+        self.prepend = Cursor::new(prepend);
+    }
+
+    /// Frees the prepend data once it's drained, so that it isn't kept around
+    /// for the rest of the stream's life.
+    pub fn compact(&mut self) {
+        if self.is_plain() || !self.pending_prepend_data().is_empty() {
+            return;
+        }
+        self.prepend = Cursor::new(Vec::new());
+    }
+
+    /// Reads into `buf` without consuming the data: it'll be returned again
+    /// by the subsequent reads.
+    pub async fn peek(&mut self, buf: &mut [u8]) -> Result<usize> {
+        let n = self.read(buf).await?;
+        self.unshift(&buf[..n]);
+        self.bytes_read -= n as u64;
+        Ok(n)
+    }
+
+    /// Like `read_exact`, but on failure also tells how many bytes were read,
+    /// which are left at the start of `buf`. Running into EOF before `buf` is
+    /// filled is `UnexpectedEof`.
+    pub async fn read_exact_counted(
+        &mut self,
+        buf: &mut [u8],
+    ) -> std::result::Result<(), (usize, Error)> {
+        let mut filled = 0;
+        while filled < buf.len() {
+            match self.read(&mut buf[filled..]).await {
+                Ok(0) => {
+                    let err = Error::new(
+                        ErrorKind::UnexpectedEof,
+                        format!("stream ended after {} of {} bytes", filled, buf.len()),
+                    );
+                    return Err((filled, err));
+                }
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == ErrorKind::Interrupted => continue,
+                Err(err) => return Err((filled, err)),
             }
-            PrependIoStream::Plain(stream) => (stream, None),
         }
+        Ok(())
+    }
+
+    /// Reads everything that's left, i.e. the pending prepend data followed by
+    /// the inner stream data up to EOF.
+    pub async fn read_remaining(&mut self) -> Result<Vec<u8>> {
+        let mut buf = Vec::with_capacity(self.pending_prepend_data().len());
+        self.read_to_end(&mut buf).await?;
+        Ok(buf)
+    }
+
+    /// Closes the inner stream, and then fails with `InvalidData` if there's
+    /// pending prepend data left, as it was never read and is now lost.
+    pub async fn close_checked(&mut self) -> Result<()> {
+        self.close().await?;
+        let pending = self.pending_prepend_data().len();
+        if pending != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("closed with {} bytes of prepend data unread", pending),
+            ));
+        }
+        Ok(())
+    }
+
+    /// Transforms the inner stream, e.g. wraps it in another adapter, keeping
+    /// the pending prepend data in front of it.
+    pub fn map_inner<U, F>(self, f: F) -> PrependIoStream<U>
+    where
+        U: AsyncRead + AsyncWrite + Unpin,
+        F: FnOnce(T) -> U,
+    {
+        PrependIoStream {
+            stream: f(self.stream),
+            prepend: self.prepend,
+            bytes_read: self.bytes_read,
+            hold_writes: self.hold_writes,
+        }
+    }
+
+    /// Turns the stream into a sink of frames, writing each one prefixed with
+    /// its length as a big-endian `u32`. Frames longer than that are
+    /// rejected with `InvalidInput`.
+    pub fn frame_sink<B>(self) -> impl Sink<B, Error = Error>
+    where
+        B: AsRef<[u8]>,
+    {
+        self.into_sink()
+            .with(|frame: B| future::ready(length_prefixed(frame.as_ref())))
+    }
+
+    /// Unwraps the inner stream, but only if there's no pending prepend data
+    /// left, so that nothing gets lost. Otherwise, returns `self` back.
+    pub fn try_into_inner(self) -> std::result::Result<T, Self> {
+        if !self.pending_prepend_data().is_empty() {
+            return Err(self);
+        }
+        let (stream, _) = self.into_inner();
+        Ok(stream)
     }
 
     pub fn pending_prepend_data(&self) -> &[u8] {
-        match self {
-            PrependIoStream::Chain(chain) => {
-                let (cursor, _) = chain.get_ref();
-                let pos = cursor.position() as usize;
-                let vec = cursor.get_ref();
-                &vec[pos..]
-            }
-            PrependIoStream::Plain(_) => &[],
+        let vec = self.prepend.get_ref();
+        let pos = (self.prepend.position() as usize).min(vec.len());
+        &vec[pos..]
+    }
+
+    /// How far the reads went into the prepend data, `0` if there's none.
+    pub fn prepend_position(&self) -> u64 {
+        self.prepend.position()
+    }
+
+    /// The length of the whole prepend data, both read and pending.
+    pub fn prepend_total(&self) -> usize {
+        self.prepend.get_ref().len()
+    }
+
+    /// The inner stream, for writing and seeking.
+    fn stream_pin_mut(&mut self) -> Pin<&mut T> {
+        Pin::new(&mut self.stream)
+    }
+
+    /// Accounts for the bytes yielded by a read.
+    fn count(&mut self, poll: Poll<Result<usize>>) -> Poll<Result<usize>> {
+        if let Poll::Ready(Ok(n)) = poll {
+            self.bytes_read += n as u64;
         }
+        poll
+    }
+}
+
+/// Prefixes the `frame` with its length as a big-endian `u32`.
+fn length_prefixed(frame: &[u8]) -> Result<Vec<u8>> {
+    let len = u32::try_from(frame.len()).map_err(|_| {
+        Error::new(
+            ErrorKind::InvalidInput,
+            format!("frame of {} bytes is too long", frame.len()),
+        )
+    })?;
+    let mut buf = Vec::with_capacity(4 + frame.len());
+    buf.extend_from_slice(&len.to_be_bytes());
+    buf.extend_from_slice(frame);
+    Ok(buf)
+}
+
+impl<T> From<T> for PrependIoStream<T>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    fn from(stream: T) -> Self {
+        Self::plain(stream)
     }
 }
 
@@ -73,12 +302,13 @@ where
         cx: &mut Context<'_>,
         buf: &mut [u8],
     ) -> Poll<Result<usize>> {
-        match self.get_mut() {
-            PrependIoStream::Plain(ref mut stream) => {
-                AsyncRead::poll_read(Pin::new(stream), cx, buf)
-            }
-            PrependIoStream::Chain(ref mut chain) => AsyncRead::poll_read(Pin::new(chain), cx, buf),
-        }
+        let this = self.get_mut();
+        let poll = if this.pending_prepend_data().is_empty() {
+            AsyncRead::poll_read(Pin::new(&mut this.stream), cx, buf)
+        } else {
+            AsyncRead::poll_read(Pin::new(&mut this.prepend), cx, buf)
+        };
+        this.count(poll)
     }
 
     fn poll_read_vectored(
@@ -86,14 +316,13 @@ where
         cx: &mut Context<'_>,
         bufs: &mut [IoSliceMut<'_>],
     ) -> Poll<Result<usize>> {
-        match self.get_mut() {
-            PrependIoStream::Plain(ref mut stream) => {
-                AsyncRead::poll_read_vectored(Pin::new(stream), cx, bufs)
-            }
-            PrependIoStream::Chain(ref mut chain) => {
-                AsyncRead::poll_read_vectored(Pin::new(chain), cx, bufs)
-            }
-        }
+        let this = self.get_mut();
+        let poll = if this.pending_prepend_data().is_empty() {
+            AsyncRead::poll_read_vectored(Pin::new(&mut this.stream), cx, bufs)
+        } else {
+            AsyncRead::poll_read_vectored(Pin::new(&mut this.prepend), cx, bufs)
+        };
+        this.count(poll)
     }
 }
 
@@ -102,15 +331,9 @@ where
     T: AsyncRead + AsyncWrite + Unpin,
 {
     fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
-        match self.get_mut() {
-            PrependIoStream::Plain(ref mut stream) => {
-                AsyncWrite::poll_write(Pin::new(stream), cx, buf)
-            }
-            PrependIoStream::Chain(chain) => {
-                let (_, stream) = chain.get_mut();
-                AsyncWrite::poll_write(Pin::new(stream), cx, buf)
-            }
-        }
+        let this = self.get_mut();
+        this.check_writable()?;
+        AsyncWrite::poll_write(this.stream_pin_mut(), cx, buf)
     }
 
     fn poll_write_vectored(
@@ -118,42 +341,41 @@ where
         cx: &mut Context<'_>,
         bufs: &[IoSlice<'_>],
     ) -> Poll<Result<usize>> {
-        match self.get_mut() {
-            PrependIoStream::Plain(ref mut stream) => {
-                AsyncWrite::poll_write_vectored(Pin::new(stream), cx, bufs)
-            }
-            PrependIoStream::Chain(chain) => {
-                let (_, stream) = chain.get_mut();
-                AsyncWrite::poll_write_vectored(Pin::new(stream), cx, bufs)
-            }
-        }
+        let this = self.get_mut();
+        this.check_writable()?;
+        AsyncWrite::poll_write_vectored(this.stream_pin_mut(), cx, bufs)
     }
 
     fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        match self.get_mut() {
-            PrependIoStream::Plain(ref mut stream) => AsyncWrite::poll_flush(Pin::new(stream), cx),
-            PrependIoStream::Chain(chain) => {
-                let (_, stream) = chain.get_mut();
-                AsyncWrite::poll_flush(Pin::new(stream), cx)
-            }
-        }
+        AsyncWrite::poll_flush(self.get_mut().stream_pin_mut(), cx)
     }
 
     fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
-        match self.get_mut() {
-            PrependIoStream::Plain(ref mut stream) => AsyncWrite::poll_close(Pin::new(stream), cx),
-            PrependIoStream::Chain(chain) => {
-                let (_, stream) = chain.get_mut();
-                AsyncWrite::poll_close(Pin::new(stream), cx)
-            }
+        AsyncWrite::poll_close(self.get_mut().stream_pin_mut(), cx)
+    }
+}
+
+/// Seeking is delegated to the inner stream, and is only possible once the
+/// prepend data is drained, since it can't be seeked over.
+impl<T> AsyncSeek for PrependIoStream<T>
+where
+    T: AsyncRead + AsyncWrite + AsyncSeek + Unpin,
+{
+    fn poll_seek(self: Pin<&mut Self>, cx: &mut Context<'_>, pos: SeekFrom) -> Poll<Result<u64>> {
+        if !self.pending_prepend_data().is_empty() {
+            return Poll::Ready(Err(Error::new(
+                ErrorKind::InvalidInput,
+                "unable to seek while prepend data is pending",
+            )));
         }
+        AsyncSeek::poll_seek(self.get_mut().stream_pin_mut(), cx, pos)
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use futures::executor;
+    use futures::{executor, AsyncSeekExt};
     use merge_io::MergeIO;
 
     #[test]
@@ -204,4 +426,432 @@ mod tests {
             Ok(())
         })
     }
+
+    #[test]
+    fn from_stream_test() {
+        let reader = Cursor::new(vec![1, 2, 3, 4]);
+        let writer = Cursor::new(vec![0u8; 1024]);
+        let stream: PrependIoStream<_> = MergeIO::new(reader, writer).into();
+
+        assert!(stream.is_plain());
+        assert!(stream.pending_prepend_data().is_empty());
+    }
+
+    #[test]
+    fn try_into_inner_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![1, 2, 3, 4]);
+            let writer = Cursor::new(vec![0u8; 1024]);
+            let stream = MergeIO::new(reader, writer);
+
+            let mut stream = PrependIoStream::from_vec(stream, Some(vec![50, 60, 70, 80]));
+
+            // Expect to get the stream back while prepend data is pending.
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await?;
+            let mut stream = match stream.try_into_inner() {
+                Ok(_) => panic!("expected an error with pending prepend data"),
+                Err(stream) => stream,
+            };
+            assert_eq!(stream.pending_prepend_data(), &[70, 80]);
+
+            // Expect to unwrap once the prepend data is drained.
+            stream.read_exact(&mut buf).await?;
+            let stream = match stream.try_into_inner() {
+                Ok(stream) => stream,
+                Err(_) => panic!("expected prepend data to be drained"),
+            };
+            let (reader, _) = stream.into_inner();
+            assert_eq!(reader.position(), 0);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn get_ref_and_get_mut_test() {
+        let reader = Cursor::new(vec![1, 2, 3, 4]);
+        let writer = Cursor::new(vec![0u8; 1024]);
+        let mut plain = PrependIoStream::from_vec(MergeIO::new(reader, writer), None);
+
+        let reader = Cursor::new(vec![1, 2, 3, 4]);
+        let writer = Cursor::new(vec![0u8; 1024]);
+        let mut chain = PrependIoStream::from_vec(MergeIO::new(reader, writer), Some(vec![50]));
+
+        for stream in [&mut plain, &mut chain].iter_mut() {
+            stream.get_mut().reader_mut().set_position(2);
+            assert_eq!(stream.get_ref().reader().position(), 2);
+        }
+    }
+
+    #[test]
+    fn plain_seek_test() -> Result<()> {
+        executor::block_on(async {
+            let mut stream = PrependIoStream::from_vec(Cursor::new(vec![1, 2, 3, 4]), None);
+
+            assert_eq!(stream.seek(SeekFrom::Start(2)).await?, 2);
+
+            let mut buf = vec![];
+            stream.read_to_end(&mut buf).await?;
+            assert_eq!(buf.as_slice(), &[3, 4]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn pending_prepend_seek_test() -> Result<()> {
+        executor::block_on(async {
+            let mut stream =
+                PrependIoStream::from_vec(Cursor::new(vec![1, 2, 3, 4]), Some(vec![50, 60]));
+
+            let err = stream.seek(SeekFrom::Start(2)).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidInput);
+
+            // Expect seeking to work after the prepend data is drained.
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(stream.seek(SeekFrom::Start(3)).await?, 3);
+
+            let mut buf = vec![];
+            stream.read_to_end(&mut buf).await?;
+            assert_eq!(buf.as_slice(), &[4]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn read_remaining_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![1, 2, 3, 4]);
+            let writer = Cursor::new(vec![0u8; 1024]);
+            let stream = MergeIO::new(reader, writer);
+
+            let mut stream = PrependIoStream::from_vec(stream, Some(vec![50, 60, 70, 80]));
+
+            let buf = stream.read_remaining().await?;
+            assert_eq!(buf.as_slice(), &[50, 60, 70, 80, 1, 2, 3, 4]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn peek_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![1, 2, 3, 4]);
+            let writer = Cursor::new(vec![0u8; 1024]);
+            let stream = MergeIO::new(reader, writer);
+
+            let mut stream = PrependIoStream::from_vec(stream, Some(vec![50, 60]));
+
+            let mut buf = [0u8; 3];
+            let n = stream.peek(&mut buf).await?;
+            assert_eq!(&buf[..n], &[50, 60]);
+
+            // Drain the prepend data, then peek into the stream.
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await?;
+            let mut buf = [0u8; 3];
+            let n = stream.peek(&mut buf).await?;
+            assert_eq!(&buf[..n], &[1, 2, 3]);
+            assert_eq!(stream.pending_prepend_data(), &[1, 2, 3]);
+
+            let buf = stream.read_remaining().await?;
+            assert_eq!(buf.as_slice(), &[1, 2, 3, 4]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn unshift_plain_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![1, 2, 3, 4]);
+            let writer = Cursor::new(vec![0u8; 1024]);
+            let mut stream = PrependIoStream::from_vec(MergeIO::new(reader, writer), None);
+
+            stream.unshift(&[50, 60]);
+            assert!(!stream.is_plain());
+
+            let buf = stream.read_remaining().await?;
+            assert_eq!(buf.as_slice(), &[50, 60, 1, 2, 3, 4]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn compact_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![1, 2, 3, 4]);
+            let writer = Cursor::new(vec![0u8; 1024]);
+            let stream = MergeIO::new(reader, writer);
+
+            let mut stream = PrependIoStream::from_vec(stream, Some(vec![50, 60]));
+
+            // Expect no effect while prepend data is pending.
+            stream.compact();
+            assert!(!stream.is_plain());
+
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await?;
+            stream.compact();
+            assert!(stream.is_plain());
+
+            let buf = stream.read_remaining().await?;
+            assert_eq!(buf.as_slice(), &[1, 2, 3, 4]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn bytes_read_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![1, 2, 3, 4]);
+            let writer = Cursor::new(vec![0u8; 1024]);
+            let stream = MergeIO::new(reader, writer);
+
+            let mut stream = PrependIoStream::from_vec(stream, Some(vec![50, 60, 70]));
+            assert_eq!(stream.bytes_read(), 0);
+
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(stream.bytes_read(), 2);
+
+            // Crosses from the prepend data over to the inner stream.
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(stream.bytes_read(), 4);
+
+            // Peeking doesn't count.
+            let n = stream.peek(&mut buf).await?;
+            assert_eq!(n, 2);
+            assert_eq!(stream.bytes_read(), 4);
+
+            let (mut first, mut second) = ([0u8; 2], [0u8; 8]);
+            let mut total = 0;
+            loop {
+                let mut bufs = [IoSliceMut::new(&mut first), IoSliceMut::new(&mut second)];
+                let n = stream.read_vectored(&mut bufs).await?;
+                if n == 0 {
+                    break;
+                }
+                total += n;
+            }
+            assert_eq!(total, 3);
+            assert_eq!(stream.bytes_read(), 7);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn from_vec_capped_test() -> Result<()> {
+        executor::block_on(async {
+            let stream = Cursor::new(vec![1, 2]);
+            let mut stream = PrependIoStream::from_vec_capped(stream, Some(vec![50, 60]), 2)?;
+            let buf = stream.read_remaining().await?;
+            assert_eq!(buf.as_slice(), &[50, 60, 1, 2]);
+
+            let stream = Cursor::new(vec![1, 2]);
+            let err =
+                PrependIoStream::from_vec_capped(stream, Some(vec![50, 60, 70]), 2).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn close_checked_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![1, 2, 3, 4]);
+            let writer = Cursor::new(vec![0u8; 1024]);
+            let stream = MergeIO::new(reader, writer);
+
+            let mut stream = PrependIoStream::from_vec(stream, Some(vec![50, 60]));
+
+            let mut buf = [0u8; 1];
+            stream.read_exact(&mut buf).await?;
+            let err = stream.close_checked().await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+            assert_eq!(stream.pending_prepend_data(), &[60]);
+
+            stream.read_exact(&mut buf).await?;
+            stream.close_checked().await?;
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn prepend_position_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![1, 2, 3, 4]);
+            let writer = Cursor::new(vec![0u8; 1024]);
+            let stream = MergeIO::new(reader, writer);
+
+            let mut stream = PrependIoStream::from_vec(stream, Some(vec![50, 60, 70]));
+            assert_eq!(stream.prepend_position(), 0);
+            assert_eq!(stream.prepend_total(), 3);
+
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(stream.prepend_position(), 2);
+            assert_eq!(stream.prepend_total(), 3);
+
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(stream.prepend_position(), 3);
+
+            stream.compact();
+            assert_eq!(stream.prepend_position(), 0);
+            assert_eq!(stream.prepend_total(), 0);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn map_inner_test() -> Result<()> {
+        /// A wrapper that counts the writes.
+        struct Counting<T> {
+            inner: T,
+            writes: usize,
+        }
+
+        impl<T: AsyncRead + Unpin> AsyncRead for Counting<T> {
+            fn poll_read(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &mut [u8],
+            ) -> Poll<Result<usize>> {
+                Pin::new(&mut self.inner).poll_read(cx, buf)
+            }
+        }
+
+        impl<T: AsyncWrite + Unpin> AsyncWrite for Counting<T> {
+            fn poll_write(
+                mut self: Pin<&mut Self>,
+                cx: &mut Context<'_>,
+                buf: &[u8],
+            ) -> Poll<Result<usize>> {
+                self.writes += 1;
+                Pin::new(&mut self.inner).poll_write(cx, buf)
+            }
+
+            fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+                Pin::new(&mut self.inner).poll_flush(cx)
+            }
+
+            fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+                Pin::new(&mut self.inner).poll_close(cx)
+            }
+        }
+
+        executor::block_on(async {
+            let reader = Cursor::new(vec![1, 2, 3, 4]);
+            let writer = Cursor::new(vec![0u8; 1024]);
+            let stream = MergeIO::new(reader, writer);
+
+            let mut stream = PrependIoStream::from_vec(stream, Some(vec![50, 60, 70]));
+            let mut buf = [0u8; 1];
+            stream.read_exact(&mut buf).await?;
+
+            let mut stream = stream.map_inner(|inner| Counting { inner, writes: 0 });
+            assert_eq!(stream.pending_prepend_data(), &[60, 70]);
+            assert_eq!(stream.bytes_read(), 1);
+
+            stream.write_all(b"hello").await?;
+            assert_eq!(stream.get_ref().writes, 1);
+
+            let buf = stream.read_remaining().await?;
+            assert_eq!(buf.as_slice(), &[60, 70, 1, 2, 3, 4]);
+
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn frame_sink_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![]);
+            let mut writer = Cursor::new(vec![]);
+            let stream = PrependIoStream::plain(MergeIO::new(reader, &mut writer));
+
+            let mut sink = Box::pin(stream.frame_sink());
+            sink.send(&b"hello"[..]).await?;
+            sink.send(&b"!"[..]).await?;
+            drop(sink);
+
+            assert_eq!(writer.get_ref().as_slice(), b"\0\0\0\x05hello\0\0\0\x01!");
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn hold_writes_test() -> Result<()> {
+        executor::block_on(async {
+            let reader = Cursor::new(vec![]);
+            let writer = Cursor::new(vec![]);
+            let mut stream =
+                PrependIoStream::from_vec(MergeIO::new(reader, writer), Some(vec![1, 2]));
+
+            // Writes go through right away by default.
+            stream.write_all(&[10]).await?;
+
+            stream.set_hold_writes(true);
+            let err = stream.write_all(&[20]).await.unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::Other);
+
+            let mut buf = [0u8; 2];
+            stream.read_exact(&mut buf).await?;
+            stream.write_all(&[30]).await?;
+
+            let (stream, _) = stream.into_inner();
+            assert_eq!(stream.writer().get_ref().as_slice(), &[10, 30]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn replace_inner_test() -> Result<()> {
+        executor::block_on(async {
+            let old = MergeIO::new(Cursor::new(vec![1, 2]), Cursor::new(vec![]));
+            let mut stream = PrependIoStream::from_vec(old, Some(vec![50, 60]));
+
+            let mut buf = [0u8; 1];
+            stream.read_exact(&mut buf).await?;
+            assert_eq!(buf, [50]);
+
+            let new = MergeIO::new(Cursor::new(vec![3, 4]), Cursor::new(vec![]));
+            let old = stream.replace_inner(new);
+            assert_eq!(old.reader().position(), 0);
+
+            let mut rest = Vec::new();
+            stream.read_to_end(&mut rest).await?;
+            assert_eq!(rest, [60, 3, 4]);
+            Ok(())
+        })
+    }
+
+    #[test]
+    fn read_exact_counted_test() -> Result<()> {
+        executor::block_on(async {
+            let stream = MergeIO::new(Cursor::new(vec![3, 4]), Cursor::new(vec![]));
+            let mut stream = PrependIoStream::from_vec(stream, Some(vec![1, 2]));
+
+            let mut buf = [0u8; 3];
+            stream.read_exact_counted(&mut buf).await.unwrap();
+            assert_eq!(buf, [1, 2, 3]);
+
+            let mut buf = [0u8; 3];
+            let (read, err) = stream.read_exact_counted(&mut buf).await.unwrap_err();
+            assert_eq!(read, 1);
+            assert_eq!(buf[..read], [4]);
+            assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+            Ok(())
+        })
+    }
 }
@@ -0,0 +1,48 @@
+//! TLS over the established tunnel, backed by `futures-rustls`.
+//!
+//! ```no_run
+//! # use futures::io::{AsyncRead, AsyncWrite};
+//! # use futures_rustls::{rustls::ClientConfig, TlsConnector};
+//! # use http_proxy_client_async::{handshake_and_wrap, tls, HeaderMap, Outcome};
+//! # use std::sync::Arc;
+//! # async fn example<S>(socket: S, config: ClientConfig) -> std::io::Result<()>
+//! # where
+//! #     S: AsyncRead + AsyncWrite + Unpin,
+//! # {
+//! let mut read_buf = [0u8; 1024];
+//! let Outcome { stream, .. } =
+//!     handshake_and_wrap(socket, "example.com", 443, &HeaderMap::new(), &mut read_buf).await?;
+//!
+//! let connector = TlsConnector::from(Arc::new(config));
+//! let tls_stream = tls::connect(&connector, "example.com", stream).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+use crate::Stream;
+use futures_io::{AsyncRead, AsyncWrite};
+use futures_rustls::client::TlsStream;
+use futures_rustls::rustls::pki_types::ServerName;
+use futures_rustls::TlsConnector;
+use std::convert::TryFrom;
+use std::io::{Error, ErrorKind, Result};
+
+pub use futures_rustls;
+
+/// Establishes a TLS session with `server_name` over the tunnel.
+///
+/// Any data that arrived along with the handshake response is still pending
+/// in the `stream`, and is fed to the TLS session before anything else is
+/// read from the underlying stream.
+pub async fn connect<T>(
+    connector: &TlsConnector,
+    server_name: &str,
+    stream: Stream<T>,
+) -> Result<TlsStream<Stream<T>>>
+where
+    T: AsyncRead + AsyncWrite + Unpin,
+{
+    let server_name = ServerName::try_from(server_name.to_owned())
+        .map_err(|err| Error::new(ErrorKind::InvalidInput, err))?;
+    connector.connect(server_name, stream).await
+}
@@ -3,6 +3,7 @@
 use futures::{executor, io::Cursor, AsyncReadExt};
 use http_proxy_client_async::*;
 use merge_io::MergeIO;
+use std::convert::TryFrom;
 
 #[test]
 fn handshake_test() -> std::io::Result<()> {
@@ -36,10 +37,13 @@ fn handshake_test() -> std::io::Result<()> {
                     headers: response_headers,
                     ..
                 },
+            trailing_len,
+            ..
         } = handshake_and_wrap(socket, "127.0.0.1", 8080, &request_headers, &mut read_buf).await?;
 
         // Verify the response was good.
         assert_eq!(code, 200);
+        assert_eq!(trailing_len, "this is already the proxied content".len());
         assert_eq!(response_headers.len(), 1);
         assert_eq!(response_headers.get("x-custom").unwrap(), &"Sample Value");
 
@@ -86,3 +90,606 @@ fn handshake_test() -> std::io::Result<()> {
         Ok(())
     })
 }
+
+#[test]
+fn handshake_with_auth_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let expected_req = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                            Host: 127.0.0.1:8080\r\n\
+                            proxy-authorization: Basic aGVsbG86d29ybGQ=\r\n\
+                            \r\n";
+        let mut responses = vec![
+            "HTTP/1.1 200 OK\r\n\
+             \r\n\
+             this is already the proxied content",
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\
+             Proxy-Authenticate: Basic realm=\"proxy\"\r\n\
+             \r\n",
+        ];
+
+        let mut connections = 0;
+        let connect = || {
+            connections += 1;
+            let reader = Cursor::new(responses.pop().unwrap());
+            let writer = Cursor::new(vec![0u8; 1024]);
+            async move { Ok(MergeIO::new(reader, writer)) }
+        };
+
+        let mut read_buf = [0u8; 1024];
+        let Outcome {
+            stream: mut tunnel_socket,
+            response_parts,
+            ..
+        } = handshake_with_auth(
+            connect,
+            "127.0.0.1",
+            8080,
+            &HeaderMap::new(),
+            "hello",
+            "world",
+            &mut read_buf,
+        )
+        .await?;
+
+        assert_eq!(connections, 2);
+        assert_eq!(response_parts.status_code, 200);
+
+        let mut data_at_tunnel = vec![];
+        tunnel_socket.read_to_end(&mut data_at_tunnel).await?;
+        assert_eq!(
+            data_at_tunnel,
+            "this is already the proxied content".as_bytes()
+        );
+
+        // Ensure the retried request carried the credentials.
+        let (unwrapped_socket, _) = tunnel_socket.into_inner();
+        let (_, writer) = unwrapped_socket.into_inner();
+        assert_eq!(
+            &writer.get_ref()[..writer.position() as usize],
+            expected_req.as_bytes(),
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn handshake_without_trailing_data_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let writer = Cursor::new(vec![0u8; 1024]);
+        let socket = MergeIO::new(reader, writer);
+
+        let mut read_buf = [0u8; 1024];
+        let outcome =
+            handshake_and_wrap(socket, "127.0.0.1", 8080, &HeaderMap::new(), &mut read_buf).await?;
+
+        assert_eq!(outcome.response_parts.status_code, 200);
+        assert!(outcome.stream.is_plain());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn try_handshake_rejected_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let reader = Cursor::new(
+            "HTTP/1.1 502 Bad Gateway\r\n\
+             Content-Length: 26\r\n\
+             \r\n\
+             upstream is not responding",
+        );
+        let writer = Cursor::new(vec![0u8; 1024]);
+        let socket = MergeIO::new(reader, writer);
+
+        let mut read_buf = [0u8; 16];
+        let (err, mut stream) =
+            match try_handshake(socket, "127.0.0.1", 8080, &HeaderMap::new(), &mut read_buf).await?
+            {
+                Ok(_) => panic!("expected the handshake to be rejected"),
+                Err(rejection) => rejection,
+            };
+
+        assert_eq!(err.response_parts.status_code, 502);
+        assert_eq!(err.response_parts.reason_phrase, "Bad Gateway");
+
+        let mut body = vec![];
+        stream.read_to_end(&mut body).await?;
+        assert_eq!(body, "upstream is not responding".as_bytes());
+
+        Ok(())
+    })
+}
+
+#[test]
+fn try_handshake_accepted_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let writer = Cursor::new(vec![0u8; 1024]);
+        let socket = MergeIO::new(reader, writer);
+
+        let mut read_buf = [0u8; 1024];
+        let outcome = try_handshake(socket, "127.0.0.1", 8080, &HeaderMap::new(), &mut read_buf)
+            .await?
+            .unwrap_or_else(|(err, _)| panic!("unexpected rejection: {}", err));
+        assert_eq!(outcome.response_parts.status_code, 200);
+
+        Ok(())
+    })
+}
+
+#[test]
+fn outcome_from_parts_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        // The handshake was done elsewhere, and only its results are at hand.
+        let (response_parts, consumed) =
+            flow::parse_response(b"HTTP/1.1 200 Connection established\r\n\r\n")?;
+        assert_eq!(consumed, 39);
+
+        let reader = Cursor::new("and the rest of it");
+        let writer = Cursor::new(vec![0u8; 1024]);
+        let socket = MergeIO::new(reader, writer);
+
+        let Outcome {
+            response_parts,
+            mut stream,
+            trailing_len,
+            ..
+        } = Outcome::from_parts(response_parts, socket, b"leftover ".to_vec());
+        assert_eq!(response_parts.status_code, 200);
+        assert_eq!(trailing_len, 9);
+
+        let mut data = String::new();
+        stream.read_to_string(&mut data).await?;
+        assert_eq!(data, "leftover and the rest of it");
+
+        Ok(())
+    })
+}
+
+#[test]
+fn handshake_and_wrap_or_return_test() {
+    executor::block_on(async {
+        let reader = Cursor::new("SSH-2.0-OpenSSH_8.9\r\n\r\n");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let mut read_buf = [0u8; 1024];
+        let (err, socket) = match handshake_and_wrap_or_return(
+            socket,
+            "127.0.0.1",
+            8080,
+            &HeaderMap::new(),
+            &mut read_buf,
+        )
+        .await
+        {
+            Ok(_) => panic!("expected the handshake to fail"),
+            Err(failure) => failure,
+        };
+
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidData);
+        assert!(socket
+            .writer()
+            .get_ref()
+            .starts_with(b"CONNECT 127.0.0.1:8080 HTTP/1.1\r\n"));
+    })
+}
+
+#[test]
+fn handshake_chain_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let expected_req = "CONNECT proxy-b:3128 HTTP/1.1\r\n\
+                            Host: proxy-b:3128\r\n\
+                            \r\n\
+                            CONNECT example.com:443 HTTP/1.1\r\n\
+                            Host: example.com:443\r\n\
+                            x-hop: b\r\n\
+                            \r\n";
+        // Both responses, and the tunneled data, arrive in a single read.
+        let reader = Cursor::new(
+            "HTTP/1.1 200 Connection established\r\n\
+             \r\n\
+             HTTP/1.1 200 OK\r\n\
+             Proxy-Agent: proxy-b\r\n\
+             \r\n\
+             tunneled data",
+        );
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let mut b_headers = HeaderMap::new();
+        b_headers.insert("X-Hop", HeaderValue::from_static("b"));
+        let hops = [
+            Hop {
+                authority: Authority::new("proxy-b", 3128)?,
+                headers: &HeaderMap::new(),
+            },
+            Hop {
+                authority: Authority::new("example.com", 443)?,
+                headers: &b_headers,
+            },
+        ];
+
+        let mut read_buf = [0u8; 1024];
+        let Outcome {
+            response_parts,
+            mut stream,
+            trailing_len,
+            ..
+        } = handshake_chain(socket, &hops, &mut read_buf).await?;
+
+        assert_eq!(response_parts.status_code, 200);
+        assert_eq!(response_parts.headers["proxy-agent"], "proxy-b");
+        assert_eq!(trailing_len, 13);
+
+        let mut data = String::new();
+        stream.read_to_string(&mut data).await?;
+        assert_eq!(data, "tunneled data");
+
+        let (socket, _) = stream.into_inner();
+        assert_eq!(
+            socket.writer().get_ref().as_slice(),
+            expected_req.as_bytes()
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn handshake_chain_stops_on_rejection_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let reader = Cursor::new("HTTP/1.1 403 Forbidden\r\n\r\n");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let headers = HeaderMap::new();
+        let hops = [
+            Hop {
+                authority: Authority::new("proxy-b", 3128)?,
+                headers: &headers,
+            },
+            Hop {
+                authority: Authority::new("example.com", 443)?,
+                headers: &headers,
+            },
+        ];
+
+        let mut read_buf = [0u8; 1024];
+        let outcome = handshake_chain(socket, &hops, &mut read_buf).await?;
+        assert_eq!(outcome.response_parts.status_code, 403);
+
+        let (socket, _) = outcome.stream.into_inner();
+        assert!(!socket
+            .writer()
+            .get_ref()
+            .windows(7)
+            .any(|w| w == b"example"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn capture_request_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let expected_req = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                            Host: 127.0.0.1:8080\r\n\
+                            x-first: 1\r\n\
+                            \r\n";
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert("X-First", HeaderValue::from_static("1"));
+        let mut config = HandshakeConfig::default();
+        config.capture_request = true;
+
+        let mut read_buf = [0u8; 1024];
+        let outcome = handshake_and_wrap_with_config(
+            socket,
+            &config,
+            "127.0.0.1",
+            8080,
+            &request_headers,
+            &mut read_buf,
+        )
+        .await?;
+
+        assert_eq!(outcome.request.as_deref(), Some(expected_req.as_bytes()));
+        let (socket, _) = outcome.stream.into_inner();
+        assert_eq!(
+            socket.writer().get_ref().as_slice(),
+            expected_req.as_bytes()
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn outcome_as_mut_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\nworld");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let mut read_buf = [0u8; 1024];
+        let mut outcome =
+            handshake_and_wrap(socket, "127.0.0.1", 8080, &HeaderMap::new(), &mut read_buf).await?;
+
+        AsMut::<Stream<_>>::as_mut(&mut outcome).unshift(b"hello ");
+
+        let mut data = String::new();
+        outcome.stream.read_to_string(&mut data).await?;
+        assert_eq!(data, "hello world");
+
+        Ok(())
+    })
+}
+
+#[test]
+fn outcome_into_stream_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\nhello world");
+        let writer = Cursor::new(vec![]);
+        let mut socket = MergeIO::new(reader, writer);
+
+        // Small enough for the tunneled data to be split between the
+        // leftover and the socket.
+        let mut read_buf = [0u8; 24];
+        let outcome = flow::handshake(
+            &mut socket,
+            "127.0.0.1",
+            8080,
+            &HeaderMap::new(),
+            &mut read_buf,
+        )
+        .await?;
+        assert!(outcome.has_trailing_data());
+
+        let mut stream = outcome.into_stream(socket);
+        let mut data = String::new();
+        stream.read_to_string(&mut data).await?;
+        assert_eq!(data, "hello world");
+
+        Ok(())
+    })
+}
+
+#[test]
+fn try_handshake_with_success_predicate_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let mut config = HandshakeConfig::default();
+        config.success = Some(|parts| {
+            parts.status_code == 200 && parts.reason_phrase == "Connection established"
+        });
+        let mut read_buf = [0u8; 1024];
+
+        let reader = Cursor::new("HTTP/1.1 200 Connection established\r\n\r\n");
+        let socket = MergeIO::new(reader, Cursor::new(vec![]));
+        let result = try_handshake_with_config(
+            socket,
+            &config,
+            "127.0.0.1",
+            8080,
+            &HeaderMap::new(),
+            &mut read_buf,
+        )
+        .await?;
+        assert!(result.is_ok());
+
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let socket = MergeIO::new(reader, Cursor::new(vec![]));
+        let result = try_handshake_with_config(
+            socket,
+            &config,
+            "127.0.0.1",
+            8080,
+            &HeaderMap::new(),
+            &mut read_buf,
+        )
+        .await?;
+        let (err, _) = result.unwrap_err();
+        assert_eq!(err.response_parts.reason_phrase, "OK");
+
+        Ok(())
+    })
+}
+
+#[test]
+fn outcome_leftover_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\nhello world");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let mut read_buf = [0u8; 1024];
+        let mut outcome =
+            handshake_and_wrap(socket, "127.0.0.1", 8080, &HeaderMap::new(), &mut read_buf).await?;
+        assert_eq!(outcome.leftover(), b"hello world");
+
+        let mut hello = [0u8; 6];
+        outcome.stream.read_exact(&mut hello).await?;
+        assert_eq!(outcome.leftover(), b"world");
+
+        let (response_parts, _socket, leftover) = outcome.into_parts();
+        assert_eq!(response_parts.status_code, 200);
+        assert_eq!(leftover, b"world");
+
+        Ok(())
+    })
+}
+
+#[test]
+fn outcome_error_for_status_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let mut read_buf = [0u8; 1024];
+
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let socket = MergeIO::new(reader, Cursor::new(vec![]));
+        let outcome =
+            handshake_and_wrap(socket, "127.0.0.1", 8080, &HeaderMap::new(), &mut read_buf).await?;
+        let outcome = outcome.error_for_status().unwrap();
+        assert_eq!(outcome.response_parts.status_code, 200);
+
+        let reader = Cursor::new(
+            "HTTP/1.1 407 Proxy Authentication Required\r\n\
+             Proxy-Authenticate: Basic realm=\"proxy\"\r\n\
+             \r\n",
+        );
+        let socket = MergeIO::new(reader, Cursor::new(vec![]));
+        let outcome =
+            handshake_and_wrap(socket, "127.0.0.1", 8080, &HeaderMap::new(), &mut read_buf).await?;
+        let err = outcome.error_for_status().unwrap_err();
+        assert_eq!(err.response_parts.status_code, 407);
+        assert!(err
+            .response_parts
+            .headers
+            .contains_key("proxy-authenticate"));
+
+        Ok(())
+    })
+}
+
+#[test]
+fn handshake_builder_basic_auth_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let expected_req = "CONNECT 127.0.0.1:8080 HTTP/1.1\r\n\
+                            Host: 127.0.0.1:8080\r\n\
+                            proxy-authorization: Basic aGVsbG86d29ybGQ=\r\n\
+                            \r\n";
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let builder = HandshakeBuilder::new().basic_auth("hello", "world");
+        let mut read_buf = [0u8; 1024];
+        let outcome = builder
+            .handshake_and_wrap(socket, "127.0.0.1", 8080, &mut read_buf)
+            .await?;
+
+        let (socket, _) = outcome.stream.into_inner();
+        assert_eq!(
+            socket.writer().get_ref().as_slice(),
+            expected_req.as_bytes()
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn handshake_builder_authority_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let expected_req = "CONNECT [::1]:443 HTTP/1.1\r\n\
+                            Host: [::1]:443\r\n\
+                            \r\n";
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let authority = Authority::try_from("[::1]:443")?;
+        let mut read_buf = [0u8; 1024];
+        let outcome = HandshakeBuilder::new()
+            .handshake_and_wrap_to(socket, &authority, &mut read_buf)
+            .await?;
+
+        let (socket, _) = outcome.stream.into_inner();
+        assert_eq!(
+            socket.writer().get_ref().as_slice(),
+            expected_req.as_bytes()
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn handshake_authority_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let expected_req = "CONNECT [fe80::1%25eth0]:443 HTTP/1.1\r\n\
+                            Host: [fe80::1%25eth0]:443\r\n\
+                            \r\n";
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let authority = Authority::try_from("[fe80::1%25eth0]:443")?;
+        let mut read_buf = [0u8; 1024];
+        let outcome =
+            handshake_and_wrap_to(socket, &authority, &HeaderMap::new(), &mut read_buf).await?;
+
+        let (socket, _) = outcome.stream.into_inner();
+        assert_eq!(
+            socket.writer().get_ref().as_slice(),
+            expected_req.as_bytes()
+        );
+
+        Ok(())
+    })
+}
+
+#[test]
+fn handshake_invalid_host_test() {
+    executor::block_on(async {
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let mut read_buf = [0u8; 1024];
+        let (err, socket) = handshake_and_wrap_or_return(
+            socket,
+            "user@example.com",
+            443,
+            &HeaderMap::new(),
+            &mut read_buf,
+        )
+        .await
+        .unwrap_err();
+        assert_eq!(err.kind(), std::io::ErrorKind::InvalidInput);
+        // Nothing was sent.
+        assert!(socket.writer().get_ref().is_empty());
+    })
+}
+
+#[test]
+fn handshake_chain_with_success_predicate_test() -> std::io::Result<()> {
+    executor::block_on(async {
+        let mut config = HandshakeConfig::default();
+        config.success = Some(|parts| parts.reason_phrase == "Connection established");
+
+        let reader = Cursor::new("HTTP/1.1 200 OK\r\n\r\n");
+        let writer = Cursor::new(vec![]);
+        let socket = MergeIO::new(reader, writer);
+
+        let headers = HeaderMap::new();
+        let hops = [
+            Hop {
+                authority: Authority::new("proxy-b", 3128)?,
+                headers: &headers,
+            },
+            Hop {
+                authority: Authority::new("example.com", 443)?,
+                headers: &headers,
+            },
+        ];
+
+        let mut read_buf = [0u8; 1024];
+        let outcome = handshake_chain_with_config(socket, &config, &hops, &mut read_buf).await?;
+        assert_eq!(outcome.response_parts.reason_phrase, "OK");
+
+        // The 200 without the expected reason phrase stopped the chain.
+        let (socket, _) = outcome.stream.into_inner();
+        assert!(!socket
+            .writer()
+            .get_ref()
+            .windows(7)
+            .any(|w| w == b"example"));
+
+        Ok(())
+    })
+}
@@ -0,0 +1,344 @@
+#![warn(missing_debug_implementations, rust_2018_idioms)]
+
+use futures::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::{executor, future};
+use futures_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer, PrivatePkcs8KeyDer};
+use futures_rustls::rustls::{ClientConfig, RootCertStore, ServerConfig};
+use futures_rustls::{TlsAcceptor, TlsConnector};
+use http_proxy_client_async::*;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::rc::Rc;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+/// One direction of an in-memory connection.
+#[derive(Debug, Default)]
+struct Pipe {
+    buf: VecDeque<u8>,
+    closed: bool,
+    waker: Option<Waker>,
+}
+
+/// One end of an in-memory duplex connection. The clones share the end.
+#[derive(Debug, Clone)]
+struct Duplex {
+    read: Arc<Mutex<Pipe>>,
+    write: Arc<Mutex<Pipe>>,
+}
+
+fn duplex() -> (Duplex, Duplex) {
+    let a = Arc::new(Mutex::new(Pipe::default()));
+    let b = Arc::new(Mutex::new(Pipe::default()));
+    (
+        Duplex {
+            read: a.clone(),
+            write: b.clone(),
+        },
+        Duplex { read: b, write: a },
+    )
+}
+
+impl AsyncRead for Duplex {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut pipe = self.read.lock().unwrap();
+        if pipe.buf.is_empty() && !pipe.closed {
+            pipe.waker = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let n = buf.len().min(pipe.buf.len());
+        for (dst, src) in buf.iter_mut().zip(pipe.buf.drain(..n)) {
+            *dst = src;
+        }
+        Poll::Ready(Ok(n))
+    }
+}
+
+impl AsyncWrite for Duplex {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let mut pipe = self.write.lock().unwrap();
+        pipe.buf.extend(buf);
+        if let Some(waker) = pipe.waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        let mut pipe = self.write.lock().unwrap();
+        pipe.closed = true;
+        if let Some(waker) = pipe.waker.take() {
+            waker.wake();
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+fn tls_configs() -> (ClientConfig, ServerConfig) {
+    let provider = Arc::new(futures_rustls::rustls::crypto::ring::default_provider());
+
+    let cert = rcgen::generate_simple_self_signed(vec!["example.com".to_owned()]).unwrap();
+    let cert_der = CertificateDer::from(cert.cert);
+    let key_der = PrivateKeyDer::from(PrivatePkcs8KeyDer::from(cert.signing_key.serialize_der()));
+
+    let mut roots = RootCertStore::empty();
+    roots.add(cert_der.clone()).unwrap();
+
+    let client_config = ClientConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_root_certificates(roots)
+        .with_no_client_auth();
+    let server_config = ServerConfig::builder_with_provider(provider)
+        .with_safe_default_protocol_versions()
+        .unwrap()
+        .with_no_client_auth()
+        .with_single_cert(vec![cert_der], key_der)
+        .unwrap();
+    (client_config, server_config)
+}
+
+#[test]
+fn tls_over_tunnel_test() -> std::io::Result<()> {
+    let (client_config, server_config) = tls_configs();
+    let (client_socket, mut proxy_socket) = duplex();
+
+    let proxy = async move {
+        // Consume the CONNECT request.
+        let mut request = vec![];
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            proxy_socket.read_exact(&mut byte).await?;
+            request.extend_from_slice(&byte);
+        }
+        assert!(request.starts_with(b"CONNECT example.com:443 HTTP/1.1\r\n"));
+
+        proxy_socket
+            .write_all(b"HTTP/1.1 200 Connection established\r\n\r\n")
+            .await?;
+
+        // Act as the origin server from now on.
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let mut tls_stream = acceptor.accept(proxy_socket).await?;
+        let mut buf = [0u8; 4];
+        tls_stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        tls_stream.write_all(b"pong").await?;
+        tls_stream.flush().await?;
+        Ok::<_, std::io::Error>(())
+    };
+
+    let client = async move {
+        let mut read_buf = [0u8; 1024];
+        let Outcome {
+            stream,
+            response_parts,
+            ..
+        } = handshake_and_wrap(
+            client_socket,
+            "example.com",
+            443,
+            &HeaderMap::new(),
+            &mut read_buf,
+        )
+        .await?;
+        assert_eq!(response_parts.status_code, 200);
+
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let mut tls_stream = tls::connect(&connector, "example.com", stream).await?;
+        tls_stream.write_all(b"ping").await?;
+        tls_stream.flush().await?;
+        let mut buf = [0u8; 4];
+        tls_stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"pong");
+        Ok::<_, std::io::Error>(())
+    };
+
+    let (proxy_result, client_result) = executor::block_on(future::join(proxy, client));
+    proxy_result?;
+    client_result
+}
+
+/// Prefixes the first write with the `prefix`, so that both go out together.
+struct PrefixFirstWrite<T> {
+    inner: T,
+    prefix: Option<&'static [u8]>,
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for PrefixFirstWrite<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for PrefixFirstWrite<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let prefix = match self.prefix.take() {
+            Some(prefix) => prefix,
+            None => return Pin::new(&mut self.inner).poll_write(cx, buf),
+        };
+        let data = [prefix, buf].concat();
+        // The duplex takes all of the data at once.
+        match Pin::new(&mut self.inner).poll_write(cx, &data) {
+            Poll::Ready(Ok(n)) if n == data.len() => Poll::Ready(Ok(buf.len())),
+            poll => panic!("the prefixed write didn't go through: {:?}", poll),
+        }
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_close(cx)
+    }
+}
+
+/// The wrapped stream, once the handshake hands it over, along with the
+/// waker of the one waiting for it.
+type Handover = Rc<RefCell<(Option<Stream<Duplex>>, Option<Waker>)>>;
+
+/// Pipelines the TLS handshake after the `CONNECT` request: the writes go
+/// out right away, and the reads wait for the handshake to hand over the
+/// wrapped stream.
+struct Pipelined {
+    write: Duplex,
+    read: Handover,
+}
+
+impl AsyncRead for Pipelined {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let (stream, waker) = &mut *self.read.borrow_mut();
+        match stream {
+            Some(stream) => Pin::new(stream).poll_read(cx, buf),
+            None => {
+                *waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl AsyncWrite for Pipelined {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.write).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.write).poll_close(cx)
+    }
+}
+
+#[test]
+fn tls_with_pending_data_test() -> std::io::Result<()> {
+    let (client_config, server_config) = tls_configs();
+    let (client_socket, mut proxy_socket) = duplex();
+
+    let proxy = async move {
+        let mut request = vec![];
+        while !request.ends_with(b"\r\n\r\n") {
+            let mut byte = [0u8; 1];
+            proxy_socket.read_exact(&mut byte).await?;
+            request.extend_from_slice(&byte);
+        }
+        assert!(request.starts_with(b"CONNECT example.com:443 HTTP/1.1\r\n"));
+
+        // The response goes out along with the first TLS bytes of the origin
+        // server, so that they're read together.
+        let proxy_socket = PrefixFirstWrite {
+            inner: proxy_socket,
+            prefix: Some(b"HTTP/1.1 200 Connection established\r\n\r\n"),
+        };
+        let acceptor = TlsAcceptor::from(Arc::new(server_config));
+        let mut tls_stream = acceptor.accept(proxy_socket).await?;
+        let mut buf = [0u8; 4];
+        tls_stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"ping");
+        tls_stream.write_all(b"pong").await?;
+        tls_stream.flush().await?;
+        Ok::<_, std::io::Error>(())
+    };
+
+    let handover = Handover::default();
+    let pipelined = Pipelined {
+        write: client_socket.clone(),
+        read: handover.clone(),
+    };
+
+    let handshake = async move {
+        let mut read_buf = [0u8; 1024];
+        let Outcome {
+            stream,
+            response_parts,
+            ..
+        } = handshake_and_wrap(
+            client_socket,
+            "example.com",
+            443,
+            &HeaderMap::new(),
+            &mut read_buf,
+        )
+        .await?;
+        assert_eq!(response_parts.status_code, 200);
+        assert!(!stream.pending_prepend_data().is_empty());
+
+        let (slot, waker) = &mut *handover.borrow_mut();
+        *slot = Some(stream);
+        if let Some(waker) = waker.take() {
+            waker.wake();
+        }
+        Ok::<_, std::io::Error>(())
+    };
+
+    // Polled after the handshake sent the request, so the ClientHello follows it.
+    let client = async move {
+        let connector = TlsConnector::from(Arc::new(client_config));
+        let stream = Stream::plain(pipelined);
+        let mut tls_stream = tls::connect(&connector, "example.com", stream).await?;
+        tls_stream.write_all(b"ping").await?;
+        tls_stream.flush().await?;
+        let mut buf = [0u8; 4];
+        tls_stream.read_exact(&mut buf).await?;
+        assert_eq!(&buf, b"pong");
+        Ok::<_, std::io::Error>(())
+    };
+
+    let (proxy_result, (handshake_result, client_result)) =
+        executor::block_on(future::join(proxy, future::join(handshake, client)));
+    proxy_result?;
+    handshake_result?;
+    client_result
+}